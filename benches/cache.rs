@@ -0,0 +1,222 @@
+// Cache hit throughput of the sharded cache, which copies a shared pre-encoded buffer on a hit,
+// against the previous cache, a single tokio Mutex around an LruCache that cloned, adjusted,
+// and encoded a Message on every hit.  The sharded cache is also run with a single shard to
+// separate the gain from sharding from the gain from pre-encoding.
+//
+//   cargo bench --bench cache
+
+// This is a binary crate, so the cache modules are compiled into the benchmark directly.  Their
+// unit tests are not built with harness = false, which leaves the test module imports unused.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/doh/cache.rs"]
+pub mod cache;
+#[path = "../src/doh/config.rs"]
+pub mod config;
+#[path = "../src/doh/request_key.rs"]
+pub mod request_key;
+
+mod doh {
+    pub use crate::{config, request_key};
+}
+
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use trust_dns_proto::op::{Message, MessageType, Query};
+use trust_dns_proto::rr::resource::Record;
+use trust_dns_proto::rr::{Name, RData, RecordType};
+
+use crate::cache::{Cache, CacheObject};
+use crate::config::CacheConfiguration;
+use crate::request_key::RequestKey;
+
+const NUM_KEYS: usize = 1000;
+
+// With headroom since keys are not spread evenly over the shards.
+const CACHE_MAX_SIZE: usize = 4 * NUM_KEYS;
+// Lock contention needs parallel lookups, so small machines still get several threads.
+const MIN_THREADS: usize = 4;
+const TASKS_PER_THREAD: usize = 4;
+const LOOKUPS_PER_TASK: usize = 50_000;
+
+struct BaselineCache {
+    cache: tokio::sync::Mutex<lru::LruCache<RequestKey, (Message, Instant)>>,
+}
+
+impl BaselineCache {
+    async fn get_response_buffer(&self, key: &RequestKey, id: u16) -> Option<Vec<u8>> {
+        let (message, cache_time) = self.cache.lock().await.get(key)?.clone();
+
+        let seconds_in_cache = cache_time.elapsed().as_secs() as u32;
+
+        let mut message = message;
+        for record in message.take_answers() {
+            let mut record = record;
+            record.set_ttl(record.ttl().checked_sub(seconds_in_cache)?);
+            message.add_answer(record);
+        }
+        message.set_id(id);
+
+        message.to_vec().ok()
+    }
+}
+
+fn build_response_message(i: usize) -> Message {
+    let name = Name::from_ascii(format!("host{}.example.com.", i)).unwrap();
+
+    let mut message = Message::new();
+    message.set_message_type(MessageType::Response);
+    message.add_query(Query::query(name.clone(), RecordType::A));
+    for j in 0..4 {
+        message.add_answer(Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(Ipv4Addr::new(10, 0, (i / 256) as u8, j)),
+        ));
+    }
+
+    message
+}
+
+// shards None uses the configuration default.
+fn build_cache(shards: Option<usize>) -> Cache {
+    let cache_configuration: CacheConfiguration = serde_json::from_value(serde_json::json!({
+        "max_size": CACHE_MAX_SIZE,
+        "max_purges_per_timer_pop": 100,
+        "shards": shards,
+    }))
+    .unwrap();
+
+    Cache::new(cache_configuration)
+}
+
+async fn cache_lookups(cache: Arc<Cache>, keys: Arc<Vec<RequestKey>>, task: usize) -> usize {
+    let mut hits = 0;
+    for i in 0..LOOKUPS_PER_TASK {
+        let key = &keys[(task * 7919 + i) % keys.len()];
+        let now = Instant::now();
+        if let Some((cache_object, _)) = cache.get(key) {
+            if cache_object.message_buffer_for_hit(now, i as u16).is_some() {
+                hits += 1;
+            }
+        }
+    }
+    hits
+}
+
+fn run_benchmark<F, Fut>(
+    name: &str,
+    runtime: &mut tokio::runtime::Runtime,
+    num_tasks: usize,
+    keys: &Arc<Vec<RequestKey>>,
+    lookup: F,
+) where
+    F: Fn(Arc<Vec<RequestKey>>, usize) -> Fut,
+    Fut: std::future::Future<Output = usize> + Send + 'static,
+{
+    let start = Instant::now();
+
+    let hits: usize = runtime.block_on(async {
+        let handles: Vec<_> = (0..num_tasks)
+            .map(|task| tokio::spawn(lookup(Arc::clone(keys), task)))
+            .collect();
+
+        let mut hits = 0;
+        for handle in handles {
+            hits += handle.await.unwrap();
+        }
+        hits
+    });
+
+    let elapsed = start.elapsed();
+    let lookups = num_tasks * LOOKUPS_PER_TASK;
+    assert_eq!(hits, lookups);
+
+    println!(
+        "{:<10} {} lookups in {:?}: {:.0} lookups/sec",
+        name,
+        lookups,
+        elapsed,
+        (lookups as f64) / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let num_threads = std::cmp::max(
+        std::thread::available_parallelism().map_or(1, |n| n.get()),
+        MIN_THREADS,
+    );
+    let num_tasks = num_threads * TASKS_PER_THREAD;
+
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .core_threads(num_threads)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let messages: Vec<Message> = (0..NUM_KEYS).map(build_response_message).collect();
+    let keys: Arc<Vec<RequestKey>> = Arc::new(
+        messages
+            .iter()
+            .map(|message| RequestKey::try_from(message).unwrap())
+            .collect(),
+    );
+
+    let now = Instant::now();
+
+    let baseline_cache = Arc::new(BaselineCache {
+        cache: tokio::sync::Mutex::new(lru::LruCache::new(CACHE_MAX_SIZE)),
+    });
+    let sharded_cache = Arc::new(build_cache(None));
+    let single_shard_cache = Arc::new(build_cache(Some(1)));
+
+    for (key, message) in keys.iter().zip(messages) {
+        let cache_object = CacheObject::new(
+            message.to_vec().unwrap(),
+            false,
+            now,
+            Duration::from_secs(300),
+        )
+        .unwrap();
+        sharded_cache.put(key.clone(), cache_object.clone());
+        single_shard_cache.put(key.clone(), cache_object);
+        runtime
+            .block_on(baseline_cache.cache.lock())
+            .put(key.clone(), (message, now));
+    }
+
+    println!(
+        "{} threads {} tasks {} keys",
+        num_threads, num_tasks, NUM_KEYS
+    );
+
+    run_benchmark("baseline", &mut runtime, num_tasks, &keys, |keys, task| {
+        let baseline_cache = Arc::clone(&baseline_cache);
+        async move {
+            let mut hits = 0;
+            for i in 0..LOOKUPS_PER_TASK {
+                let key = &keys[(task * 7919 + i) % keys.len()];
+                if baseline_cache
+                    .get_response_buffer(key, i as u16)
+                    .await
+                    .is_some()
+                {
+                    hits += 1;
+                }
+            }
+            hits
+        }
+    });
+
+    run_benchmark("sharded", &mut runtime, num_tasks, &keys, |keys, task| {
+        cache_lookups(Arc::clone(&sharded_cache), keys, task)
+    });
+
+    run_benchmark("1 shard", &mut runtime, num_tasks, &keys, |keys, task| {
+        cache_lookups(Arc::clone(&single_shard_cache), keys, task)
+    });
+}
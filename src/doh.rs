@@ -1,10 +1,33 @@
+mod bootstrap;
 mod cache;
+mod cachesnapshot;
 mod client;
 pub mod config;
+mod connectionslot;
+mod connector;
+#[cfg(feature = "quic")]
+mod doqclient;
+mod dotclient;
+mod egressproxy;
+mod forwarding;
+#[cfg(feature = "quic")]
+mod http3client;
+mod httpcache;
+mod inflight;
 mod localdomain;
 mod metrics;
+mod odohclient;
+mod padding;
 pub mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
 mod request_key;
+mod requestqueue;
 mod tcpserver;
+#[cfg(test)]
+mod testutil;
+mod tls;
+mod udpclient;
 mod udpserver;
+mod upstream;
 mod utils;
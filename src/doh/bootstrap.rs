@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use tokio::sync::Mutex;
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_proto::rr::{Name, RData, RecordType};
+
+use crate::doh::config::{BootstrapConfiguration, ClientConfiguration};
+use crate::doh::udpclient::UDPClient;
+use crate::doh::utils;
+
+struct BootstrapDNSClient {
+    udp_client: UDPClient,
+    request_timeout_duration: Duration,
+    refresh_interval_duration: Duration,
+    last_refresh_time: Mutex<Instant>,
+}
+
+impl BootstrapDNSClient {
+    fn new(bootstrap_configuration: &BootstrapConfiguration) -> Result<Self, Box<dyn Error>> {
+        Ok(BootstrapDNSClient {
+            udp_client: UDPClient::new(bootstrap_configuration.dns_server_url())?,
+            request_timeout_duration: Duration::from_secs(
+                bootstrap_configuration.request_timeout_seconds(),
+            ),
+            refresh_interval_duration: Duration::from_secs(
+                bootstrap_configuration.refresh_interval_seconds(),
+            ),
+            last_refresh_time: Mutex::new(Instant::now()),
+        })
+    }
+
+    async fn resolve_record_type(
+        &self,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Vec<IpAddr>, Box<dyn Error>> {
+        let mut request_message = Message::new();
+        request_message.set_message_type(MessageType::Query);
+        request_message.set_op_code(OpCode::Query);
+        request_message.set_recursion_desired(true);
+        request_message.add_query(Query::query(name.clone(), record_type));
+
+        let query = request_message.queries()[0].clone();
+
+        let request_buffer = utils::encode_dns_message(request_message)?;
+
+        let response_buffer = tokio::time::timeout(
+            self.request_timeout_duration,
+            self.udp_client.make_plain_request(request_buffer),
+        )
+        .await
+        .map_err(|_| format!("bootstrap request timeout for {}", name))??;
+
+        let response_message = utils::decode_dns_message(response_buffer)?;
+
+        if (response_message.message_type() != MessageType::Response)
+            || (response_message.queries() != [query])
+        {
+            return Err(format!("bootstrap response does not answer query for {}", name).into());
+        }
+
+        Ok(response_message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::A(ip_address) => Some(IpAddr::V4(*ip_address)),
+                RData::AAAA(ip_address) => Some(IpAddr::V6(*ip_address)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Box<dyn Error>> {
+        let name = Name::from_str(host)?;
+
+        let mut ip_addresses = self.resolve_record_type(&name, RecordType::A).await?;
+        ip_addresses.extend(self.resolve_record_type(&name, RecordType::AAAA).await?);
+
+        debug!("bootstrap resolved {} to {:?}", host, ip_addresses);
+
+        Ok(ip_addresses)
+    }
+}
+
+// Returns the lowercase host of the url, or None if it has no host or the host is an ip address.
+fn url_host(url: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let url = url::Url::parse(url)?;
+
+    let host = match url.host_str() {
+        None => return Ok(None),
+        Some(host) => host.to_ascii_lowercase(),
+    };
+
+    // Url keeps the brackets around ipv6 addresses.
+    if IpAddr::from_str(host.trim_start_matches('[').trim_end_matches(']')).is_ok() {
+        return Ok(None);
+    }
+
+    Ok(Some(host))
+}
+
+// Resolves upstream host names without depending on the system resolver, which may
+// point back at this proxy.
+pub struct BootstrapResolver {
+    host_ip_addresses: RwLock<HashMap<String, Vec<IpAddr>>>,
+    // Hosts with bootstrap_ip_addresses, which are never re-resolved.
+    pinned_hosts: HashSet<String>,
+    bootstrap_dns_client: Option<BootstrapDNSClient>,
+    // The egress proxy resolves upstream host names, so they are not refreshed.
+    egress_proxy_configured: bool,
+}
+
+impl BootstrapResolver {
+    pub fn new(client_configuration: &ClientConfiguration) -> Result<Self, Box<dyn Error>> {
+        let mut host_ip_addresses = HashMap::new();
+        let mut pinned_hosts = HashSet::new();
+
+        let egress_proxy_configured = client_configuration.egress_proxy_configuration().is_some();
+
+        for upstream_configuration in client_configuration.upstream_configurations() {
+            if egress_proxy_configured
+                && !upstream_configuration.bootstrap_ip_addresses().is_empty()
+            {
+                error!(
+                    "upstream {} bootstrap_ip_addresses are ignored because an egress proxy is configured",
+                    upstream_configuration.name()
+                );
+            }
+
+            // The odoh proxy host has no pinned addresses, but is added so it gets refreshed.
+            if let Some(odoh_configuration) = upstream_configuration.odoh_configuration() {
+                if let Some(host) = url_host(odoh_configuration.proxy_url())? {
+                    host_ip_addresses.entry(host).or_default();
+                }
+            }
+
+            let host = match url_host(upstream_configuration.url())? {
+                None => continue,
+                Some(host) => host,
+            };
+
+            if !upstream_configuration.bootstrap_ip_addresses().is_empty() {
+                pinned_hosts.insert(host.clone());
+            }
+
+            let ip_addresses: &mut Vec<IpAddr> = host_ip_addresses.entry(host).or_default();
+
+            for bootstrap_ip_address in upstream_configuration.bootstrap_ip_addresses() {
+                let ip_address = IpAddr::from_str(bootstrap_ip_address).map_err(|e| {
+                    format!(
+                        "invalid bootstrap ip address {}: {}",
+                        bootstrap_ip_address, e
+                    )
+                })?;
+                if !ip_addresses.contains(&ip_address) {
+                    ip_addresses.push(ip_address);
+                }
+            }
+        }
+
+        info!("created bootstrap resolver {:?}", host_ip_addresses);
+
+        let bootstrap_dns_client = match client_configuration.bootstrap_configuration() {
+            None => None,
+            Some(bootstrap_configuration) => {
+                Some(BootstrapDNSClient::new(bootstrap_configuration)?)
+            }
+        };
+
+        Ok(BootstrapResolver {
+            host_ip_addresses: RwLock::new(host_ip_addresses),
+            pinned_hosts,
+            bootstrap_dns_client,
+            egress_proxy_configured,
+        })
+    }
+
+    fn cached_ip_addresses(&self, host: &str) -> Option<Vec<IpAddr>> {
+        match self.host_ip_addresses.read().unwrap().get(host) {
+            Some(ip_addresses) if !ip_addresses.is_empty() => Some(ip_addresses.clone()),
+            _ => None,
+        }
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        if let Ok(ip_address) = IpAddr::from_str(host) {
+            return Ok(vec![SocketAddr::new(ip_address, port)]);
+        }
+
+        let host = host.to_ascii_lowercase();
+
+        if let Some(ip_addresses) = self.cached_ip_addresses(&host) {
+            return Ok(ip_addresses
+                .into_iter()
+                .map(|ip_address| SocketAddr::new(ip_address, port))
+                .collect());
+        }
+
+        if let Some(bootstrap_dns_client) = &self.bootstrap_dns_client {
+            let ip_addresses = bootstrap_dns_client.resolve(&host).await.map_err(|e| {
+                std::io::Error::other(format!("bootstrap resolve error for {}: {}", host, e))
+            })?;
+
+            if !ip_addresses.is_empty() {
+                self.host_ip_addresses
+                    .write()
+                    .unwrap()
+                    .insert(host, ip_addresses.clone());
+            }
+
+            return Ok(ip_addresses
+                .into_iter()
+                .map(|ip_address| SocketAddr::new(ip_address, port))
+                .collect());
+        }
+
+        let socket_addresses = tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .collect();
+
+        Ok(socket_addresses)
+    }
+
+    // Re-resolve upstream hosts without pinned addresses through the bootstrap dns server if the
+    // refresh interval has passed.  Addresses are only replaced when resolution succeeds.  Run in
+    // its own task so slow lookups do not hold up the periodic timer, and skipped while a
+    // previous refresh is still running.  Never run with an egress proxy, since the addresses
+    // are unused and the queries would send the upstream host names over plain UDP.
+    pub async fn periodic_refresh(self: Arc<Self>) {
+        let bootstrap_dns_client = match &self.bootstrap_dns_client {
+            Some(bootstrap_dns_client) if !self.egress_proxy_configured => bootstrap_dns_client,
+            _ => return,
+        };
+
+        let mut last_refresh_time = match bootstrap_dns_client.last_refresh_time.try_lock() {
+            Err(_) => {
+                debug!("bootstrap refresh already running");
+                return;
+            }
+            Ok(last_refresh_time) => last_refresh_time,
+        };
+
+        let now = Instant::now();
+        if (now - *last_refresh_time) < bootstrap_dns_client.refresh_interval_duration {
+            return;
+        }
+        *last_refresh_time = now;
+
+        let hosts: Vec<String> = self
+            .host_ip_addresses
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|host| !self.pinned_hosts.contains(*host))
+            .cloned()
+            .collect();
+
+        for host in hosts {
+            let ip_addresses = match bootstrap_dns_client.resolve(&host).await {
+                Err(e) => {
+                    warn!("bootstrap refresh error for {}: {}", host, e);
+                    continue;
+                }
+                Ok(ip_addresses) => ip_addresses,
+            };
+
+            if ip_addresses.is_empty() {
+                warn!("bootstrap refresh got no addresses for {}", host);
+                continue;
+            }
+
+            info!("bootstrap refresh {} ip_addresses {:?}", host, ip_addresses);
+            self.host_ip_addresses
+                .write()
+                .unwrap()
+                .insert(host, ip_addresses);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::doh::testutil;
+
+    // Runs periodic_refresh with a bootstrap dns server that never answers, returns whether it
+    // got a query.
+    async fn refresh_sends_query(client_fields: serde_json::Value) -> bool {
+        let mut bootstrap_dns_server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut client_fields = client_fields;
+        client_fields.as_object_mut().unwrap().insert(
+            "bootstrap_configuration".to_string(),
+            serde_json::json!({
+                "dns_server_url": format!("udp://{}", bootstrap_dns_server.local_addr().unwrap()),
+                "request_timeout_seconds": 1,
+                "refresh_interval_seconds": 0,
+            }),
+        );
+        let bootstrap_resolver = Arc::new(
+            BootstrapResolver::new(&testutil::build_client_configuration_with_upstreams(
+                vec![serde_json::json!({
+                    "name": "test",
+                    "url": "https://dns.example/dns-query",
+                    "request_timeout_seconds": 5,
+                })],
+                client_fields,
+            ))
+            .unwrap(),
+        );
+
+        tokio::spawn(bootstrap_resolver.periodic_refresh());
+
+        let mut buffer = [0; 512];
+        tokio::time::timeout(
+            Duration::from_millis(500),
+            bootstrap_dns_server.recv_from(&mut buffer),
+        )
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn periodic_refresh() {
+        assert!(refresh_sends_query(serde_json::json!({})).await);
+    }
+
+    #[tokio::test]
+    async fn periodic_refresh_skipped_with_egress_proxy() {
+        assert!(
+            !refresh_sends_query(serde_json::json!({
+                "egress_proxy_configuration": {
+                    "proxy_type": "socks5",
+                    "proxy_address": "127.0.0.1:1080",
+                },
+            }))
+            .await
+        );
+    }
+}
@@ -1,97 +1,481 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
-use trust_dns_proto::op::Message;
-
-use crate::doh::config::CacheConfiguration;
+use crate::doh::config::{CacheConfiguration, PrefetchConfiguration};
 use crate::doh::request_key::RequestKey;
 
+// RFC 6891 section 6.1.3: the OPT record TTL field holds EDNS flags, not a TTL.
+const OPT_RECORD_TYPE: u16 = 41;
+
+// Skips a possibly compressed domain name, RFC 1035 section 4.1.4.
+fn skip_name(buffer: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let label_length = *buffer.get(offset)?;
+        match label_length & 0xC0 {
+            0x00 if label_length == 0 => return Some(offset + 1),
+            0x00 => offset += 1 + usize::from(label_length),
+            0xC0 => return Some(offset + 2),
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *buffer.get(offset)?,
+        *buffer.get(offset + 1)?,
+    ]))
+}
+
+// Offsets of the TTL field of each resource record in a wire format message.
+fn record_ttl_offsets(buffer: &[u8]) -> Option<Vec<usize>> {
+    let query_count = read_u16(buffer, 4)?;
+    let record_count = usize::from(read_u16(buffer, 6)?)
+        + usize::from(read_u16(buffer, 8)?)
+        + usize::from(read_u16(buffer, 10)?);
+
+    let mut offset = 12;
+
+    for _ in 0..query_count {
+        offset = skip_name(buffer, offset)? + 4;
+    }
+
+    let mut ttl_offsets = Vec::with_capacity(record_count);
+
+    for _ in 0..record_count {
+        offset = skip_name(buffer, offset)?;
+        if read_u16(buffer, offset)? != OPT_RECORD_TYPE {
+            ttl_offsets.push(offset + 4);
+        }
+        let rdata_length = read_u16(buffer, offset + 8)?;
+        offset += 10 + usize::from(rdata_length);
+    }
+
+    if offset != buffer.len() {
+        return None;
+    }
+
+    Some(ttl_offsets)
+}
+
+// The message is stored encoded with ID 0 and shared between clones, so a cache hit copies the
+// buffer and patches the ID and TTLs instead of cloning and encoding a Message.
 #[derive(Clone)]
 pub struct CacheObject {
-    message: Message,
+    message_buffer: Arc<[u8]>,
+    ttl_offsets: Arc<[usize]>,
+    negative_response: bool,
     cache_time: Instant,
     expiration_time: Instant,
+    hits: u64,
+    prefetch_started: bool,
 }
 
 impl CacheObject {
-    pub fn new(message: Message, cache_time: Instant, cache_duration: Duration) -> Self {
+    pub fn new(
+        message_buffer: Vec<u8>,
+        negative_response: bool,
+        cache_time: Instant,
+        cache_duration: Duration,
+    ) -> Result<Self, &'static str> {
+        let ttl_offsets =
+            record_ttl_offsets(&message_buffer).ok_or("invalid cache object message buffer")?;
         let expiration_time = cache_time + cache_duration;
-        CacheObject {
-            message,
+        Ok(CacheObject {
+            message_buffer: message_buffer.into(),
+            ttl_offsets: ttl_offsets.into(),
+            negative_response,
             cache_time,
             expiration_time,
+            hits: 0,
+            prefetch_started: false,
+        })
+    }
+
+    pub fn negative_response(&self) -> bool {
+        self.negative_response
+    }
+
+    // Copies the message buffer with the given ID, mapping each record TTL with set_ttl.
+    fn build_message_buffer(
+        &self,
+        id: u16,
+        set_ttl: impl Fn(u32) -> Option<u32>,
+    ) -> Option<Vec<u8>> {
+        let mut message_buffer = self.message_buffer.to_vec();
+
+        message_buffer[..2].copy_from_slice(&id.to_be_bytes());
+
+        for &ttl_offset in self.ttl_offsets.iter() {
+            let ttl_bytes = &mut message_buffer[ttl_offset..(ttl_offset + 4)];
+            let ttl = u32::from_be_bytes([ttl_bytes[0], ttl_bytes[1], ttl_bytes[2], ttl_bytes[3]]);
+            ttl_bytes.copy_from_slice(&set_ttl(ttl)?.to_be_bytes());
         }
+
+        Some(message_buffer)
+    }
+
+    // Record TTLs are reduced by the time in cache.  Returns None if any record TTL has run out.
+    pub fn message_buffer_for_hit(&self, now: Instant, id: u16) -> Option<Vec<u8>> {
+        let seconds_in_cache = self.duration_in_cache(now).as_secs();
+
+        self.build_message_buffer(id, |ttl| {
+            u64::from(ttl)
+                .checked_sub(seconds_in_cache)
+                .map(|ttl| ttl as u32)
+        })
     }
 
-    pub fn message(self) -> Message {
-        self.message
+    pub fn message_buffer_with_ttl(&self, ttl: u32, id: u16) -> Vec<u8> {
+        self.build_message_buffer(id, |_| Some(ttl))
+            .expect("set_ttl always returns a ttl")
     }
 
-    pub fn message_mut(&mut self) -> &mut Message {
-        &mut self.message
+    pub fn message_buffer_with_reduced_ttls(&self, seconds: u64, id: u16) -> Vec<u8> {
+        self.build_message_buffer(
+            id,
+            |ttl| Some(u64::from(ttl).saturating_sub(seconds) as u32),
+        )
+        .expect("set_ttl always returns a ttl")
     }
 
     pub fn expired(&self, now: Instant) -> bool {
         now > self.expiration_time
     }
 
+    // True once the entry is too old to be served even as a stale response.
+    pub fn stale_expired(&self, now: Instant, stale_window: Duration) -> bool {
+        now > (self.expiration_time + stale_window)
+    }
+
     pub fn duration_in_cache(&self, now: Instant) -> Duration {
         now - self.cache_time
     }
+
+    pub fn remaining_duration(&self, now: Instant) -> Duration {
+        self.expiration_time.saturating_duration_since(now)
+    }
+
+    fn should_prefetch(
+        &self,
+        now: Instant,
+        prefetch_configuration: &PrefetchConfiguration,
+    ) -> bool {
+        if self.prefetch_started || (self.hits < prefetch_configuration.min_hits()) {
+            return false;
+        }
+
+        let cache_duration = self.expiration_time - self.cache_time;
+        let remaining_duration = self.expiration_time.saturating_duration_since(now);
+
+        (remaining_duration.as_millis() * 100)
+            < (cache_duration.as_millis()
+                * u128::from(prefetch_configuration.remaining_ttl_percent()))
+    }
 }
 
+type CacheShard = Mutex<lru::LruCache<RequestKey, CacheObject>>;
+
+// Entries are spread over independently locked LRU shards by a hash of the RequestKey, so
+// lookups of different keys usually take different locks.  Each shard holds an equal part of
+// max_size and evicts its own least recently used entries, so the cache can evict before it
+// holds max_size entries.
 pub struct Cache {
     cache_configuration: CacheConfiguration,
-    cache: Mutex<lru::LruCache<RequestKey, CacheObject>>,
+    stale_window: Duration,
+    hash_builder: RandomState,
+    shards: Vec<CacheShard>,
 }
 
 impl Cache {
     pub fn new(cache_configuration: CacheConfiguration) -> Self {
-        let max_size = cache_configuration.max_size();
+        let num_shards = std::cmp::max(cache_configuration.shards(), 1);
+        let max_shard_size = std::cmp::max(cache_configuration.max_size().div_ceil(num_shards), 1);
+
+        // Expired entries are kept for the serve-stale window.
+        let stale_window = Duration::from_secs(
+            cache_configuration
+                .serve_stale_configuration()
+                .map_or(0, |serve_stale_configuration| {
+                    serve_stale_configuration.stale_window_seconds()
+                }),
+        );
 
         Cache {
             cache_configuration,
-            cache: Mutex::new(lru::LruCache::new(max_size)),
+            stale_window,
+            hash_builder: RandomState::new(),
+            shards: (0..num_shards)
+                .map(|_| Mutex::new(lru::LruCache::new(max_shard_size)))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &RequestKey) -> &CacheShard {
+        let hash = self.hash_builder.hash_one(key);
+        &self.shards[(hash % (self.shards.len() as u64)) as usize]
+    }
+
+    // Returns the cache object, and true if the caller should prefetch it.  Each cache object is
+    // only prefetched once, a successful prefetch replaces it.
+    pub fn get(&self, key: &RequestKey) -> Option<(CacheObject, bool)> {
+        let mut mut_shard = self.shard(key).lock().unwrap();
+
+        let cache_object = mut_shard.get_mut(key)?;
+
+        let now = Instant::now();
+        let mut prefetch = false;
+
+        if !cache_object.expired(now) {
+            cache_object.hits += 1;
+
+            if let Some(prefetch_configuration) = self.cache_configuration.prefetch_configuration()
+            {
+                prefetch = cache_object.should_prefetch(now, prefetch_configuration);
+                if prefetch {
+                    cache_object.prefetch_started = true;
+                }
+            }
         }
+
+        Some((cache_object.clone(), prefetch))
     }
 
-    pub async fn get(&self, key: &RequestKey) -> Option<CacheObject> {
-        let mut mut_cache = self.cache.lock().await;
+    // Clones of the unexpired cache objects, least recently used first within each shard.
+    pub fn unexpired_cache_objects(&self) -> Vec<CacheObject> {
+        let now = Instant::now();
+
+        let mut cache_objects = Vec::new();
 
-        match mut_cache.get(key) {
-            Some(v) => Some(v.clone()),
-            None => None,
+        for shard in &self.shards {
+            let mut_shard = shard.lock().unwrap();
+
+            cache_objects.extend(
+                mut_shard
+                    .iter()
+                    .rev()
+                    .map(|(_, cache_object)| cache_object)
+                    .filter(|cache_object| !cache_object.expired(now))
+                    .cloned(),
+            );
         }
+
+        cache_objects
     }
 
-    pub async fn put(&self, key: RequestKey, cache_object: CacheObject) {
-        let mut mut_cache = self.cache.lock().await;
+    pub fn put(&self, key: RequestKey, cache_object: CacheObject) {
+        let mut mut_shard = self.shard(&key).lock().unwrap();
 
-        mut_cache.put(key, cache_object);
+        mut_shard.put(key, cache_object);
     }
 
-    pub async fn periodic_purge(&self) -> (usize, usize) {
-        let mut mut_cache = self.cache.lock().await;
+    // Returns the number of entries and the number purged.  max_purges_per_timer_pop is split
+    // between the shards.
+    pub fn periodic_purge(&self) -> (usize, usize) {
+        let max_shard_purges = self
+            .cache_configuration
+            .max_purges_per_timer_pop()
+            .div_ceil(self.shards.len());
 
+        let mut len = 0;
         let mut items_purged = 0;
 
         let now = Instant::now();
 
-        while items_purged < self.cache_configuration.max_purges_per_timer_pop() {
-            let lru_key_and_value = match mut_cache.peek_lru() {
-                None => break,
-                Some(lru_key_and_value) => lru_key_and_value,
-            };
-
-            if lru_key_and_value.1.expired(now) {
-                let key_clone = lru_key_and_value.0.clone();
-                mut_cache.pop(&key_clone);
-                items_purged += 1;
-            } else {
-                break;
+        for shard in &self.shards {
+            let mut mut_shard = shard.lock().unwrap();
+
+            let mut shard_items_purged = 0;
+
+            while shard_items_purged < max_shard_purges {
+                let lru_key_and_value = match mut_shard.peek_lru() {
+                    None => break,
+                    Some(lru_key_and_value) => lru_key_and_value,
+                };
+
+                if lru_key_and_value.1.stale_expired(now, self.stale_window) {
+                    let key_clone = lru_key_and_value.0.clone();
+                    mut_shard.pop(&key_clone);
+                    shard_items_purged += 1;
+                } else {
+                    break;
+                }
             }
+
+            len += mut_shard.len();
+            items_purged += shard_items_purged;
+        }
+
+        (len, items_purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use trust_dns_proto::op::{Edns, Message, MessageType, Query};
+    use trust_dns_proto::rr::rdata::SOA;
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    // Answer, authority, and additional records all under example.com, so most names are
+    // compressed, plus an OPT record with the DO bit set.
+    fn build_message_buffer() -> Vec<u8> {
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let zone = Name::from_ascii("example.com.").unwrap();
+        let name_server = Name::from_ascii("ns.example.com.").unwrap();
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        edns.set_dnssec_ok(true);
+
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ))
+            .add_answer(Record::from_rdata(
+                name,
+                600,
+                RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+            ))
+            .add_name_server(Record::from_rdata(
+                zone.clone(),
+                3600,
+                RData::SOA(SOA::new(
+                    name_server.clone(),
+                    Name::from_ascii("hostmaster.example.com.").unwrap(),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    300,
+                )),
+            ))
+            .add_additional(Record::from_rdata(
+                name_server,
+                7200,
+                RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+            ))
+            .set_edns(edns);
+
+        message.to_vec().unwrap()
+    }
+
+    fn record_ttls(message: &Message) -> Vec<u32> {
+        message
+            .answers()
+            .iter()
+            .chain(message.name_servers())
+            .chain(message.additionals())
+            .map(Record::ttl)
+            .collect()
+    }
+
+    #[test]
+    fn skip_name_handles_pointers_and_truncation() {
+        // www.example.com, then a pointer to it.
+        let buffer = b"\x03www\x07example\x03com\x00\xC0\x00";
+
+        assert_eq!(skip_name(buffer, 0), Some(17));
+        assert_eq!(skip_name(buffer, 17), Some(19));
+        assert_eq!(skip_name(b"\x00", 0), Some(1));
+        // Reserved label types.
+        assert_eq!(skip_name(b"\x40", 0), None);
+        assert_eq!(skip_name(b"\x80", 0), None);
+        for length in 0..17 {
+            assert_eq!(skip_name(&buffer[..length], 0), None);
         }
+    }
+
+    #[test]
+    fn record_ttl_offsets_skip_opt_record() {
+        let buffer = build_message_buffer();
+        let message = Message::from_vec(&buffer).unwrap();
+
+        // Names after the first are compressed.
+        assert!(buffer.windows(2).any(|window| window == [0xC0, 0x0C]));
+        assert!(message.edns().is_some());
+
+        let ttl_offsets = record_ttl_offsets(&buffer).unwrap();
+        let ttls: Vec<u32> = ttl_offsets
+            .iter()
+            .map(|&offset| {
+                u32::from_be_bytes([
+                    buffer[offset],
+                    buffer[offset + 1],
+                    buffer[offset + 2],
+                    buffer[offset + 3],
+                ])
+            })
+            .collect();
+        assert_eq!(ttls, vec![300, 600, 3600, 7200]);
+        assert_eq!(ttls, record_ttls(&message));
+    }
+
+    #[test]
+    fn record_ttl_offsets_rejects_truncated_and_malformed_buffers() {
+        let buffer = build_message_buffer();
+
+        for length in 0..buffer.len() {
+            assert_eq!(record_ttl_offsets(&buffer[..length]), None);
+        }
+
+        let mut trailing_bytes = buffer.clone();
+        trailing_bytes.push(0);
+        assert_eq!(record_ttl_offsets(&trailing_bytes), None);
+
+        // Answer count larger than the records in the buffer.
+        let mut extra_answer_count = buffer.clone();
+        extra_answer_count[7] += 1;
+        assert_eq!(record_ttl_offsets(&extra_answer_count), None);
+
+        // Query name with a reserved label type.
+        let mut reserved_label = buffer;
+        reserved_label[12] = 0x80;
+        assert_eq!(record_ttl_offsets(&reserved_label), None);
+    }
+
+    #[test]
+    fn message_buffer_rewrites_id_and_ttls() {
+        let cache_object = CacheObject::new(
+            build_message_buffer(),
+            false,
+            Instant::now(),
+            Duration::from_secs(300),
+        )
+        .unwrap();
+
+        let message = Message::from_vec(&cache_object.message_buffer_with_ttl(42, 7)).unwrap();
+        assert_eq!(message.id(), 7);
+        assert_eq!(record_ttls(&message), vec![42, 42, 42, 42]);
+        // The OPT record TTL field holds the EDNS flags, which are left alone.
+        let edns = message.edns().unwrap();
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.max_payload(), 1232);
+
+        let message =
+            Message::from_vec(&cache_object.message_buffer_with_reduced_ttls(500, 8)).unwrap();
+        assert_eq!(message.id(), 8);
+        assert_eq!(record_ttls(&message), vec![0, 100, 3100, 6700]);
+        assert!(message.edns().unwrap().dnssec_ok());
 
-        (mut_cache.len(), items_purged)
+        let message = Message::from_vec(
+            &cache_object
+                .message_buffer_for_hit(Instant::now(), 9)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(message.id(), 9);
+        assert_eq!(record_ttls(&message), vec![300, 600, 3600, 7200]);
     }
 }
@@ -0,0 +1,287 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::resource::Record;
+
+use crate::doh::cache::{Cache, CacheObject};
+use crate::doh::request_key::RequestKey;
+use crate::doh::utils;
+
+#[derive(Deserialize, Serialize)]
+struct CacheSnapshotEntry {
+    // Base64 DNS wire format message with record TTLs as of the snapshot time.
+    message: String,
+    remaining_seconds: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CacheSnapshot {
+    // Wall clock time since Instants do not survive a restart.
+    snapshot_unix_time_seconds: u64,
+    entries: Vec<CacheSnapshotEntry>,
+}
+
+fn unix_time_seconds() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn subtract_from_record_ttls(message: Message, seconds: u64) -> Message {
+    let subtract_from_record_ttl = |record: Record| -> Record {
+        let ttl = u64::from(record.ttl()).saturating_sub(seconds);
+        let mut record = record;
+        record.set_ttl(ttl as u32);
+        record
+    };
+
+    let mut message = message;
+
+    for record in message.take_answers() {
+        message.add_answer(subtract_from_record_ttl(record));
+    }
+    for record in message.take_name_servers() {
+        message.add_name_server(subtract_from_record_ttl(record));
+    }
+    for record in message.take_additionals() {
+        message.add_additional(subtract_from_record_ttl(record));
+    }
+
+    message
+}
+
+// Writes the unexpired cache entries to file_path, returns the number of entries written.
+pub async fn write_cache_snapshot(cache: &Cache, file_path: &str) -> Result<usize, Box<dyn Error>> {
+    let snapshot_unix_time_seconds = unix_time_seconds()?;
+
+    let cache_objects = cache.unexpired_cache_objects();
+
+    let now = Instant::now();
+
+    let mut entries = Vec::with_capacity(cache_objects.len());
+
+    for cache_object in cache_objects {
+        let remaining_seconds = cache_object.remaining_duration(now).as_secs();
+        if remaining_seconds == 0 {
+            continue;
+        }
+
+        let seconds_in_cache = cache_object.duration_in_cache(now).as_secs();
+        let message_buffer = cache_object.message_buffer_with_reduced_ttls(seconds_in_cache, 0);
+
+        entries.push(CacheSnapshotEntry {
+            message: base64::encode(message_buffer),
+            remaining_seconds,
+        });
+    }
+
+    let entries_len = entries.len();
+
+    let buffer = serde_json::to_vec(&CacheSnapshot {
+        snapshot_unix_time_seconds,
+        entries,
+    })?;
+
+    // Synced and renamed into place so a crash while writing does not leave a partial snapshot.
+    // Callers must not write the same file_path concurrently since they share the temporary file.
+    let temporary_file_path = format!("{}.tmp", file_path);
+    let mut temporary_file = tokio::fs::File::create(&temporary_file_path).await?;
+    temporary_file.write_all(&buffer).await?;
+    temporary_file.sync_all().await?;
+    drop(temporary_file);
+    tokio::fs::rename(&temporary_file_path, file_path).await?;
+
+    Ok(entries_len)
+}
+
+// Loads entries from the snapshot at file_path into cache, reducing TTLs by the wall clock time
+// since the snapshot was written and dropping entries that have expired.  Returns the number of
+// entries loaded.
+pub async fn load_cache_snapshot(cache: &Cache, file_path: &str) -> Result<usize, Box<dyn Error>> {
+    let buffer = tokio::fs::read(file_path).await?;
+
+    let snapshot: CacheSnapshot = serde_json::from_slice(&buffer)?;
+
+    let elapsed_seconds = unix_time_seconds()?.saturating_sub(snapshot.snapshot_unix_time_seconds);
+
+    let now = Instant::now();
+
+    // Decode everything before touching the cache so a corrupt snapshot loads nothing.
+    let mut cache_entries = Vec::with_capacity(snapshot.entries.len());
+
+    for entry in snapshot.entries {
+        if entry.remaining_seconds <= elapsed_seconds {
+            continue;
+        }
+
+        let message = utils::decode_dns_message(base64::decode(&entry.message)?)?;
+        let request_key = RequestKey::try_from(&message)?;
+        let negative_response = utils::is_negative_response(&message);
+        let message = subtract_from_record_ttls(message, elapsed_seconds);
+
+        cache_entries.push((
+            request_key,
+            CacheObject::new(
+                utils::encode_dns_message(message)?,
+                negative_response,
+                now,
+                Duration::from_secs(entry.remaining_seconds - elapsed_seconds),
+            )?,
+        ));
+    }
+
+    let cache_entries_len = cache_entries.len();
+
+    // Entries are least recently used first within each shard they were written from, but the
+    // shard hash is random per process, so the loaded LRU order is only roughly the old one.
+    for (request_key, cache_object) in cache_entries {
+        cache.put(request_key, cache_object);
+    }
+
+    Ok(cache_entries_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use trust_dns_proto::op::{MessageType, Query};
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use crate::doh::testutil::TestFile;
+
+    fn build_cache() -> Cache {
+        Cache::new(
+            serde_json::from_value(serde_json::json!({
+                "max_size": 100,
+                "max_purges_per_timer_pop": 10,
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn build_response_message(name: &str, ttl: u32) -> Message {
+        let name = Name::from_ascii(name).unwrap();
+
+        let mut response_message = Message::new();
+        response_message
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(
+                name,
+                ttl,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+        response_message
+    }
+
+    // The cached record TTL for name, if it is in cache.
+    fn cached_ttl(cache: &Cache, name: &str) -> Option<u32> {
+        let request_key = RequestKey::try_from(&build_response_message(name, 0)).unwrap();
+        let (cache_object, _) = cache.get(&request_key)?;
+        let message_buffer = cache_object.message_buffer_for_hit(Instant::now(), 0)?;
+        Some(utils::decode_dns_message(message_buffer).unwrap().answers()[0].ttl())
+    }
+
+    fn snapshot_entry(name: &str, ttl: u32, remaining_seconds: u64) -> serde_json::Value {
+        serde_json::json!({
+            "message": base64::encode(
+                utils::encode_dns_message(build_response_message(name, ttl)).unwrap()
+            ),
+            "remaining_seconds": remaining_seconds,
+        })
+    }
+
+    #[tokio::test]
+    async fn write_and_load() {
+        let cache = build_cache();
+        for (name, ttl) in &[("a.example.com.", 300), ("b.example.com.", 600)] {
+            let response_message = build_response_message(name, *ttl);
+            cache.put(
+                RequestKey::try_from(&response_message).unwrap(),
+                CacheObject::new(
+                    utils::encode_dns_message(response_message).unwrap(),
+                    false,
+                    Instant::now(),
+                    Duration::from_secs(u64::from(*ttl)),
+                )
+                .unwrap(),
+            );
+        }
+        let snapshot_file = TestFile::new(b"");
+
+        assert_eq!(
+            write_cache_snapshot(&cache, snapshot_file.path())
+                .await
+                .unwrap(),
+            2
+        );
+
+        let loaded_cache = build_cache();
+        assert_eq!(
+            load_cache_snapshot(&loaded_cache, snapshot_file.path())
+                .await
+                .unwrap(),
+            2
+        );
+        // Allowing for a second boundary between writing and loading.
+        let ttl = cached_ttl(&loaded_cache, "a.example.com.").unwrap();
+        assert!((298..=300).contains(&ttl), "ttl {}", ttl);
+        let ttl = cached_ttl(&loaded_cache, "b.example.com.").unwrap();
+        assert!((598..=600).contains(&ttl), "ttl {}", ttl);
+    }
+
+    #[tokio::test]
+    async fn load_subtracts_time_since_snapshot() {
+        let snapshot_file = TestFile::new(
+            serde_json::json!({
+                "snapshot_unix_time_seconds": unix_time_seconds().unwrap() - 100,
+                "entries": [
+                    snapshot_entry("a.example.com.", 300, 300),
+                    snapshot_entry("expired.example.com.", 300, 50),
+                ],
+            })
+            .to_string()
+            .as_bytes(),
+        );
+
+        let cache = build_cache();
+        assert_eq!(
+            load_cache_snapshot(&cache, snapshot_file.path())
+                .await
+                .unwrap(),
+            1
+        );
+
+        let ttl = cached_ttl(&cache, "a.example.com.").unwrap();
+        assert!((199..=200).contains(&ttl), "ttl {}", ttl);
+        assert_eq!(cached_ttl(&cache, "expired.example.com."), None);
+    }
+
+    #[tokio::test]
+    async fn load_corrupt_snapshot_loads_nothing() {
+        let snapshot = serde_json::json!({
+            "snapshot_unix_time_seconds": unix_time_seconds().unwrap(),
+            "entries": [
+                snapshot_entry("a.example.com.", 300, 300),
+                {"message": "not base64!", "remaining_seconds": 300},
+            ],
+        })
+        .to_string();
+        let truncated_snapshot = &snapshot[..(snapshot.len() / 2)];
+
+        for contents in &[snapshot.as_str(), truncated_snapshot, "", "garbage"] {
+            let snapshot_file = TestFile::new(contents.as_bytes());
+            let cache = build_cache();
+
+            assert!(load_cache_snapshot(&cache, snapshot_file.path())
+                .await
+                .is_err());
+            assert!(cache.unexpired_cache_objects().is_empty());
+        }
+    }
+}
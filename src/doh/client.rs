@@ -1,28 +1,55 @@
+use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use bytes::Buf;
-use log::{debug, warn};
-use tokio::sync::{Semaphore, SemaphorePermit};
+use hyper::body::HttpBody;
+use hyper::{header, Body, Request, StatusCode, Version};
+use log::{debug, info, warn};
+use rand::Rng;
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RecordType};
 
-use crate::doh::config::ClientConfiguration;
+use crate::doh::bootstrap::BootstrapResolver;
+use crate::doh::config::{
+    ClientConfiguration, DOHRequestMethod, HealthCheckConfiguration, HedgingConfiguration,
+    LatencySelectionConfiguration, RetryConfiguration,
+};
+use crate::doh::connector::UpstreamConnector;
+use crate::doh::egressproxy::{self, EgressProxy};
+use crate::doh::forwarding::ForwardingRule;
+#[cfg(feature = "quic")]
+use crate::doh::http3client::HTTP3Client;
+use crate::doh::httpcache::HTTPCacheHeaders;
+use crate::doh::metrics::{CounterMetricType, Metrics};
+use crate::doh::padding;
+use crate::doh::requestqueue::RequestQueue;
+use crate::doh::tls;
+use crate::doh::upstream::{HTTPClient, Upstream, UpstreamTransport};
+use crate::doh::utils;
 
 #[derive(Debug)]
-enum DOHRequestErrorType {
+pub enum DOHRequestErrorType {
     TooManyOutstandingRequests,
     HTTPRequestError,
     ContentLengthTooLong,
-    ContentLengthMissing,
+    AllUpstreamsFailed,
+    ConnectionClosed,
+    SPKIPinValidationFailed,
+    RequestQueueTimeout,
+    ODOHDecryptError,
+    EgressProxyError,
 }
 
 #[derive(Debug)]
-struct DOHRequestError {
+pub struct DOHRequestError {
     error_type: DOHRequestErrorType,
 }
 
 impl DOHRequestError {
-    fn new(error_type: DOHRequestErrorType) -> Box<Self> {
+    pub fn new(error_type: DOHRequestErrorType) -> Box<Self> {
         Box::new(DOHRequestError { error_type })
     }
 }
@@ -36,7 +63,12 @@ impl fmt::Display for DOHRequestError {
                 DOHRequestErrorType::TooManyOutstandingRequests => "too many outstanding requests",
                 DOHRequestErrorType::HTTPRequestError => "http request error",
                 DOHRequestErrorType::ContentLengthTooLong => "content length too long",
-                DOHRequestErrorType::ContentLengthMissing => "content length missing"
+                DOHRequestErrorType::AllUpstreamsFailed => "all upstreams failed",
+                DOHRequestErrorType::ConnectionClosed => "connection closed",
+                DOHRequestErrorType::SPKIPinValidationFailed => "spki pin validation failed",
+                DOHRequestErrorType::RequestQueueTimeout => "request queue timeout",
+                DOHRequestErrorType::ODOHDecryptError => "odoh decrypt error",
+                DOHRequestErrorType::EgressProxyError => "egress proxy error",
             }
         )
     }
@@ -44,7 +76,32 @@ impl fmt::Display for DOHRequestError {
 
 impl Error for DOHRequestError {}
 
-const MAX_CONTENT_LENGTH: u64 = 65_535; // RFC 8484 section 6
+#[cfg(feature = "quic")]
+// True for errors where the server answered with an HTTP response that was rejected, as opposed
+// to transport and connection errors.
+pub fn is_http_response_error(error: &(dyn Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<DOHRequestError>(),
+        Some(DOHRequestError {
+            error_type: DOHRequestErrorType::HTTPRequestError
+                | DOHRequestErrorType::ContentLengthTooLong,
+        })
+    )
+}
+
+// Replaces pin validation and egress proxy failures with their own DOHRequestErrorType so they
+// are distinguishable from other connection errors.
+pub fn map_connection_error<E: Error + 'static>(error: E) -> Box<dyn Error> {
+    if tls::is_spki_pin_validation_error(&error) {
+        DOHRequestError::new(DOHRequestErrorType::SPKIPinValidationFailed)
+    } else if egressproxy::is_egress_proxy_error(&error) {
+        DOHRequestError::new(DOHRequestErrorType::EgressProxyError)
+    } else {
+        Box::new(error)
+    }
+}
+
+pub const MAX_CONTENT_LENGTH: u64 = 65_535; // RFC 8484 section 6
 
 fn validate_response_content_length(
     content_length_option: Option<u64>,
@@ -62,69 +119,1082 @@ fn validate_response_content_length(
             }
         }
         None => {
-            warn!("content_length = None");
-            Err(DOHRequestError::new(
-                DOHRequestErrorType::ContentLengthMissing,
-            ))
+            debug!("content_length = None");
+            Ok(())
+        }
+    }
+}
+
+// Reads the response body, failing once it grows past MAX_CONTENT_LENGTH so responses without a
+// content length are still size limited.
+pub async fn read_response_body(mut response_body: Body) -> Result<Vec<u8>, Box<dyn Error>> {
+    validate_response_content_length(response_body.size_hint().exact())?;
+
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response_body.data().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) as u64 > MAX_CONTENT_LENGTH {
+            warn!("got too long response body length > {}", MAX_CONTENT_LENGTH);
+            return Err(DOHRequestError::new(
+                DOHRequestErrorType::ContentLengthTooLong,
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+// Checks the upstream response answers the request, request_id being the ID sent upstream.  A
+// mismatched response counts as a failure of that upstream so the request goes on to the next
+// one, and is never cached under the request's RequestKey.
+fn validate_response_message(
+    request_id: u16,
+    request_message: &Message,
+    response_message: &Message,
+) -> Result<(), String> {
+    if response_message.id() != request_id {
+        return Err(format!(
+            "id {} does not match request id {}",
+            response_message.id(),
+            request_id
+        ));
+    }
+
+    if response_message.message_type() != MessageType::Response {
+        return Err("message type is not response".to_string());
+    }
+
+    if response_message.op_code() != request_message.op_code() {
+        return Err(format!(
+            "op code {:?} does not match request op code {:?}",
+            response_message.op_code(),
+            request_message.op_code()
+        ));
+    }
+
+    if response_message.queries().len() != request_message.queries().len() {
+        return Err(format!(
+            "query count {} does not match request query count {}",
+            response_message.queries().len(),
+            request_message.queries().len()
+        ));
+    }
+
+    for (response_query, request_query) in response_message
+        .queries()
+        .iter()
+        .zip(request_message.queries())
+    {
+        if (response_query.name() != request_query.name())
+            || (response_query.query_type() != request_query.query_type())
+            || (response_query.query_class() != request_query.query_class())
+        {
+            return Err(format!(
+                "query {} does not match request query {}",
+                response_query, request_query
+            ));
         }
     }
+
+    Ok(())
+}
+
+// An upstream response with the HTTP caching headers it came with.
+#[derive(Debug)]
+pub struct DOHResponse {
+    response_message: Message,
+    http_cache_headers: HTTPCacheHeaders,
+}
+
+impl DOHResponse {
+    pub fn response_message(&self) -> &Message {
+        &self.response_message
+    }
+
+    pub fn http_cache_headers(&self) -> &HTTPCacheHeaders {
+        &self.http_cache_headers
+    }
+
+    pub fn into_response_message(self) -> Message {
+        self.response_message
+    }
 }
 
-const DOH_MIME_TYPE: &'static str = "application/dns-message";
+pub const DOH_MIME_TYPE: &str = "application/dns-message";
+
+// RFC 8484 section 4.1: the GET request url with the base64url encoded query and no padding.
+pub fn doh_get_url(url: &str, request_buffer: &[u8]) -> Result<url::Url, Box<dyn Error>> {
+    let dns_parameter = base64::encode_config(request_buffer, base64::URL_SAFE_NO_PAD);
+    let mut url = url::Url::parse(url)?;
+    url.query_pairs_mut().append_pair("dns", &dns_parameter);
+    Ok(url)
+}
+
+// Used to track upstream stats when latency_selection_configuration is not set.
+const DEFAULT_SMOOTHING_FACTOR: f64 = 0.1;
+const DEFAULT_ERROR_PENALTY_MILLISECONDS: u64 = 1_000;
 
 pub struct DOHClient {
-    client_configuration: ClientConfiguration,
-    client: reqwest::Client,
-    request_semaphore: Semaphore,
+    bootstrap_resolver: Arc<BootstrapResolver>,
+    request_queue: RequestQueue,
+    upstreams: Vec<Upstream>,
+    latency_selection_configuration: Option<LatencySelectionConfiguration>,
+    retry_configuration: Option<RetryConfiguration>,
+    hedging_configuration: Option<HedgingConfiguration>,
+    metrics: Arc<Metrics>,
+    request_counter: AtomicU64,
+    retries: AtomicU64,
+    hedged_requests: AtomicU64,
+    health_check_probe_timeout: Duration,
+    health_checks_running: AtomicBool,
 }
 
 impl DOHClient {
-    pub fn new(client_configuration: ClientConfiguration) -> Result<Self, Box<dyn Error>> {
-        let timeout_duration = Duration::from_secs(client_configuration.request_timeout_seconds());
-        let max_outstanding_requests = client_configuration.max_outstanding_requests();
+    pub fn new(
+        client_configuration: ClientConfiguration,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if client_configuration.upstream_configurations().is_empty() {
+            return Err("client_configuration upstream_configurations is empty".into());
+        }
+
+        let latency_selection_configuration = client_configuration
+            .latency_selection_configuration()
+            .cloned();
+
+        let (smoothing_factor, error_penalty_milliseconds) = match &latency_selection_configuration
+        {
+            None => (DEFAULT_SMOOTHING_FACTOR, DEFAULT_ERROR_PENALTY_MILLISECONDS),
+            Some(latency_selection_configuration) => (
+                latency_selection_configuration.smoothing_factor(),
+                latency_selection_configuration.error_penalty_milliseconds(),
+            ),
+        };
+
+        let consecutive_failure_threshold = client_configuration
+            .health_check_configuration()
+            .map(HealthCheckConfiguration::consecutive_failure_threshold);
+
+        let health_check_probe_timeout = Duration::from_secs(
+            client_configuration
+                .health_check_configuration()
+                .map_or(0, HealthCheckConfiguration::probe_timeout_seconds),
+        );
+
+        let bootstrap_resolver = Arc::new(BootstrapResolver::new(&client_configuration)?);
+        let egress_proxy = match client_configuration.egress_proxy_configuration() {
+            None => None,
+            Some(egress_proxy_configuration) => {
+                Some(Arc::new(EgressProxy::new(egress_proxy_configuration)?))
+            }
+        };
+        let upstream_connector =
+            UpstreamConnector::new(Arc::clone(&bootstrap_resolver), egress_proxy);
+
+        let upstreams = client_configuration
+            .upstream_configurations()
+            .iter()
+            .cloned()
+            .map(|upstream_configuration| {
+                Upstream::new(
+                    upstream_configuration,
+                    &upstream_connector,
+                    smoothing_factor,
+                    error_penalty_milliseconds as f64,
+                    consecutive_failure_threshold,
+                )
+            })
+            .collect::<Result<Vec<Upstream>, Box<dyn Error>>>()?;
+
+        if !upstreams.iter().any(|upstream| upstream.use_by_default()) {
+            return Err("client_configuration has no upstreams used by default".into());
+        }
+
         Ok(DOHClient {
-            client_configuration,
-            client: reqwest::Client::builder()
-                .use_rustls_tls()
-                .timeout(timeout_duration)
-                .build()?,
-            request_semaphore: Semaphore::new(max_outstanding_requests),
+            bootstrap_resolver,
+            request_queue: RequestQueue::new(
+                client_configuration.max_outstanding_requests(),
+                client_configuration.request_queue_configuration(),
+            ),
+            upstreams,
+            latency_selection_configuration,
+            retry_configuration: client_configuration.retry_configuration().cloned(),
+            hedging_configuration: client_configuration.hedging_configuration().cloned(),
+            metrics,
+            request_counter: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            hedged_requests: AtomicU64::new(0),
+            health_check_probe_timeout,
+            health_checks_running: AtomicBool::new(false),
         })
     }
 
-    fn acquire_semaphore(&self) -> Result<SemaphorePermit<'_>, Box<DOHRequestError>> {
-        self.request_semaphore.try_acquire().map_err(|_| DOHRequestError::new(
-            DOHRequestErrorType::TooManyOutstandingRequests,
-        ))
+    fn upstreams_by_score(&self) -> Vec<(&Upstream, f64)> {
+        let mut upstreams_and_scores: Vec<(&Upstream, f64)> = self
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.use_by_default())
+            .map(|upstream| (upstream, upstream.stats().score()))
+            .collect();
+
+        if self.latency_selection_configuration.is_some() {
+            upstreams_and_scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        }
+
+        upstreams_and_scores
     }
 
-    pub async fn make_doh_request(
+    pub fn contains_upstream(&self, upstream_name: &str) -> bool {
+        self.upstreams
+            .iter()
+            .any(|upstream| upstream.name() == upstream_name)
+    }
+
+    // Skips upstreams that are marked down.  If every upstream is down they are all returned,
+    // since trying a down upstream is better than failing without trying.
+    fn healthy_upstreams(upstreams: Vec<&Upstream>) -> Vec<&Upstream> {
+        if upstreams
+            .iter()
+            .all(|upstream| !upstream.health().healthy())
+        {
+            return upstreams;
+        }
+
+        upstreams
+            .into_iter()
+            .filter(|upstream| upstream.health().healthy())
+            .collect()
+    }
+
+    // Returns upstreams in the order they should be tried for the next request.
+    fn upstream_request_order(&self, forwarding_rule: Option<&ForwardingRule>) -> Vec<&Upstream> {
+        if let Some(forwarding_rule) = forwarding_rule {
+            return self
+                .upstreams
+                .iter()
+                .filter(|upstream| upstream.name() == forwarding_rule.upstream_name())
+                .collect();
+        }
+
+        let mut upstreams: Vec<&Upstream> = Self::healthy_upstreams(
+            self.upstreams_by_score()
+                .into_iter()
+                .map(|(upstream, _)| upstream)
+                .collect(),
+        );
+
+        let exploration_interval_requests = match &self.latency_selection_configuration {
+            None => return upstreams,
+            Some(latency_selection_configuration) => {
+                latency_selection_configuration.exploration_interval_requests()
+            }
+        };
+
+        let request_count = self.request_counter.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+
+        if (upstreams.len() > 1)
+            && (exploration_interval_requests > 0)
+            && request_count.is_multiple_of(exploration_interval_requests)
+        {
+            let explorations = request_count / exploration_interval_requests;
+            let exploration_index = 1 + (explorations as usize % (upstreams.len() - 1));
+            let explored_upstream = upstreams.remove(exploration_index);
+            debug!("exploring upstream {}", explored_upstream.name());
+            upstreams.insert(0, explored_upstream);
+        }
+
+        upstreams
+    }
+
+    pub fn selected_upstream_metrics_string(&self) -> String {
+        match self.upstreams_by_score().first() {
+            None => String::new(),
+            Some((upstream, score)) => format!(
+                "selected_upstream={} selected_upstream_score={:.3}",
+                upstream.name(),
+                score
+            ),
+        }
+    }
+
+    pub fn periodic_bootstrap_refresh(&self) {
+        tokio::spawn(Arc::clone(&self.bootstrap_resolver).periodic_refresh());
+    }
+
+    pub fn request_queue_metrics_string(&self) -> String {
+        self.request_queue.metrics_string()
+    }
+
+    pub fn retry_metrics_string(&self) -> String {
+        format!(
+            "upstream_retries={} hedged_requests={}",
+            self.retries.load(AtomicOrdering::Relaxed),
+            self.hedged_requests.load(AtomicOrdering::Relaxed)
+        )
+    }
+
+    #[cfg(feature = "quic")]
+    async fn make_http3_request(
         &self,
+        upstream: &Upstream,
+        http_client: &HTTPClient,
+        http3_client: &HTTP3Client,
         request_buffer: Vec<u8>,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let _permit = self.acquire_semaphore()?;
+    ) -> Result<(Vec<u8>, HTTPCacheHeaders), Box<dyn Error>> {
+        match http3_client.make_doh_request(&request_buffer).await {
+            Ok(result) => {
+                self.metrics
+                    .counter_metric(CounterMetricType::UpstreamHTTP3Responses)
+                    .increment_value();
+                return Ok(result);
+            }
+            // HTTP/3 worked, so the upstream's error response is not a reason to fall back.
+            Err(e) if is_http_response_error(e.as_ref()) => return Err(e),
+            Err(e) => {
+                warn!(
+                    "upstream {} http3 request error {}, falling back to http2",
+                    upstream.name(),
+                    e
+                );
+                http3_client.start_fallback();
+                self.metrics
+                    .counter_metric(CounterMetricType::UpstreamHTTP3Fallbacks)
+                    .increment_value();
+            }
+        }
+
+        self.make_https_request(upstream, http_client, request_buffer)
+            .await
+    }
+
+    async fn make_https_request(
+        &self,
+        upstream: &Upstream,
+        http_client: &HTTPClient,
+        request_buffer: Vec<u8>,
+    ) -> Result<(Vec<u8>, HTTPCacheHeaders), Box<dyn Error>> {
+        let request = match upstream.request_method() {
+            DOHRequestMethod::Get => {
+                Request::get(doh_get_url(upstream.url(), &request_buffer)?.as_str())
+                    .header(header::ACCEPT, DOH_MIME_TYPE)
+                    .body(Body::empty())?
+            }
+            DOHRequestMethod::Post => Request::post(upstream.url())
+                .header(header::CONTENT_TYPE, DOH_MIME_TYPE)
+                .header(header::ACCEPT, DOH_MIME_TYPE)
+                .body(Body::from(request_buffer))?,
+        };
 
-        let response = self
-            .client
-            .post(self.client_configuration.remote_url())
-            .header(reqwest::header::CONTENT_TYPE, DOH_MIME_TYPE)
-            .header(reqwest::header::ACCEPT, DOH_MIME_TYPE)
-            .body(request_buffer)
-            .send()
-            .await?;
+        let response = http_client
+            .request(request)
+            .await
+            .map_err(map_connection_error)?;
 
-        debug!("after reqwest post response status = {}", response.status());
+        debug!("after http request response status = {}", response.status());
 
-        if response.status() != reqwest::StatusCode::OK {
+        if response.status() != StatusCode::OK {
             warn!("got http error response status {}", response.status().as_u16());
             return Err(DOHRequestError::new(DOHRequestErrorType::HTTPRequestError));
         }
 
-        validate_response_content_length(response.content_length())?;
+        if response.version() == Version::HTTP_2 {
+            self.metrics
+                .counter_metric(CounterMetricType::UpstreamHTTP2Responses)
+                .increment_value();
+        } else {
+            self.metrics
+                .counter_metric(CounterMetricType::UpstreamHTTP1Responses)
+                .increment_value();
+        }
+
+        let http_cache_headers = HTTPCacheHeaders::new(response.headers());
+        debug!("http_cache_headers = {:?}", http_cache_headers);
+
+        let response_buffer = read_response_body(response.into_body()).await?;
+
+        Ok((response_buffer, http_cache_headers))
+    }
+
+    async fn make_upstream_request(
+        &self,
+        upstream: &Upstream,
+        request_message: &Message,
+        request_buffer: Vec<u8>,
+    ) -> Result<DOHResponse, Box<dyn Error>> {
+        let request_buffer = if upstream.edns_padding() {
+            padding::pad_request_buffer(request_buffer)?
+        } else {
+            request_buffer
+        };
+
+        let request_id = match request_buffer.get(..2) {
+            Some(id_bytes) => u16::from_be_bytes([id_bytes[0], id_bytes[1]]),
+            None => return Err("request buffer too short".into()),
+        };
+
+        let (response_buffer, http_cache_headers) = match upstream.transport() {
+            #[cfg(feature = "quic")]
+            UpstreamTransport::DOH(http_client, Some(http3_client)) if http3_client.available() => {
+                self.make_http3_request(upstream, http_client, http3_client, request_buffer)
+                    .await?
+            }
+            UpstreamTransport::DOH(http_client, ..) => {
+                self.make_https_request(upstream, http_client, request_buffer)
+                    .await?
+            }
+            UpstreamTransport::DOT(dot_client) => (
+                dot_client.make_dot_request(request_buffer).await?,
+                HTTPCacheHeaders::default(),
+            ),
+            UpstreamTransport::UDP(udp_client) => (
+                udp_client.make_plain_request(request_buffer).await?,
+                HTTPCacheHeaders::default(),
+            ),
+            // ODoH responses are encrypted per query so are never served from an HTTP cache.
+            UpstreamTransport::ODOH(odoh_client) => (
+                odoh_client.make_odoh_request(request_buffer).await?,
+                HTTPCacheHeaders::default(),
+            ),
+            #[cfg(feature = "quic")]
+            UpstreamTransport::DOQ(doq_client) => (
+                doq_client.make_doq_request(request_buffer).await?,
+                HTTPCacheHeaders::default(),
+            ),
+        };
+
+        let mut response_message = utils::decode_dns_message(response_buffer)?;
+
+        padding::strip_response_padding(&mut response_message);
+
+        if let Err(e) = validate_response_message(request_id, request_message, &response_message) {
+            self.metrics
+                .counter_metric(CounterMetricType::InvalidUpstreamResponses)
+                .increment_value();
+            return Err(format!("invalid response: {}", e).into());
+        }
+
+        Ok(DOHResponse {
+            response_message,
+            http_cache_headers,
+        })
+    }
+
+    fn record_upstream_success(&self, upstream: &Upstream, response_time: Duration) {
+        upstream.stats().record_success(response_time);
+
+        if upstream.health().record_success() {
+            info!("upstream {} marked up", upstream.name());
+            self.metrics
+                .counter_metric(CounterMetricType::UpstreamMarkedUp)
+                .increment_value();
+        }
+    }
+
+    fn record_upstream_failure(&self, upstream: &Upstream, timeout: Option<Duration>) {
+        upstream.stats().record_failure(timeout);
+
+        if upstream.health().record_failure() {
+            warn!("upstream {} marked down", upstream.name());
+            self.metrics
+                .counter_metric(CounterMetricType::UpstreamMarkedDown)
+                .increment_value();
+        }
+    }
+
+    // A primary upstream abandoned for a faster hedged request counts as a timeout after the time
+    // it was given, so that a slow primary stops being selected.  Its health is left alone since
+    // it has not failed.
+    fn record_abandoned_upstream_request(&self, upstream: &Upstream, elapsed: Duration) {
+        debug!(
+            "abandoned request to upstream {} after {:?}",
+            upstream.name(),
+            elapsed
+        );
+        upstream.stats().record_failure(Some(elapsed));
+    }
+
+    // Returns the response, or None after logging the error and recording stats.
+    async fn make_upstream_request_with_timeout(
+        &self,
+        upstream: &Upstream,
+        request_message: &Message,
+        request_buffer: Vec<u8>,
+    ) -> Option<DOHResponse> {
+        let start_time = Instant::now();
+
+        match tokio::time::timeout(
+            upstream.request_timeout_duration(),
+            self.make_upstream_request(upstream, request_message, request_buffer),
+        )
+        .await
+        {
+            Ok(Ok(doh_response)) => {
+                debug!("got response from upstream {}", upstream.name());
+                self.record_upstream_success(upstream, start_time.elapsed());
+                Some(doh_response)
+            }
+            Ok(Err(e)) => {
+                warn!("upstream {} request error {}", upstream.name(), e);
+                self.record_upstream_failure(upstream, None);
+                None
+            }
+            Err(_) => {
+                warn!("upstream {} request timeout", upstream.name());
+                self.record_upstream_failure(
+                    upstream,
+                    Some(upstream.request_timeout_duration()),
+                );
+                None
+            }
+        }
+    }
+
+    fn build_health_check_request_message() -> Message {
+        let mut request_message = Message::new();
+        request_message.set_message_type(MessageType::Query);
+        request_message.set_op_code(OpCode::Query);
+        request_message.set_recursion_desired(true);
+        request_message.add_query(Query::query(Name::root(), RecordType::NS));
+
+        request_message
+    }
+
+    // Probes the upstream with a ". NS" query, marking it up if it answers with anything other
+    // than SERVFAIL.
+    async fn run_health_check(
+        &self,
+        upstream: &Upstream,
+        request_message: &Message,
+        request_buffer: Vec<u8>,
+    ) {
+        debug!("health check upstream {}", upstream.name());
+        self.metrics
+            .counter_metric(CounterMetricType::UpstreamHealthChecks)
+            .increment_value();
+
+        let start_time = Instant::now();
+
+        match tokio::time::timeout(
+            self.health_check_probe_timeout,
+            self.make_upstream_request(upstream, request_message, request_buffer),
+        )
+        .await
+        {
+            Ok(Ok(doh_response)) => {
+                if doh_response.response_message().response_code() == ResponseCode::ServFail {
+                    warn!("health check upstream {} got servfail", upstream.name());
+                } else {
+                    self.record_upstream_success(upstream, start_time.elapsed());
+                }
+            }
+            Ok(Err(e)) => {
+                warn!("health check upstream {} error {}", upstream.name(), e);
+            }
+            Err(_) => {
+                warn!("health check upstream {} timeout", upstream.name());
+            }
+        }
+    }
+
+    // Probes all upstreams that are marked down concurrently.  Skipped while the previous run's
+    // probes are still outstanding.
+    async fn run_health_checks(self: Arc<Self>) {
+        if self
+            .health_checks_running
+            .swap(true, AtomicOrdering::AcqRel)
+        {
+            debug!("health checks already running");
+            return;
+        }
+
+        let request_message = Self::build_health_check_request_message();
+
+        match utils::encode_dns_message(request_message.clone()) {
+            Err(e) => {
+                warn!("health check encode_dns_message error {}", e);
+            }
+            Ok(request_buffer) => {
+                futures::future::join_all(
+                    self.upstreams
+                        .iter()
+                        .filter(|upstream| !upstream.health().healthy())
+                        .map(|upstream| {
+                            self.run_health_check(
+                                upstream,
+                                &request_message,
+                                request_buffer.clone(),
+                            )
+                        }),
+                )
+                .await;
+            }
+        }
+
+        self.health_checks_running
+            .store(false, AtomicOrdering::Release);
+    }
+
+    // Runs the health check probes in their own task so a slow probe does not delay the
+    // periodic timer.
+    pub fn periodic_health_checks(self: &Arc<Self>) {
+        if self
+            .upstreams
+            .iter()
+            .all(|upstream| upstream.health().healthy())
+        {
+            return;
+        }
+
+        tokio::spawn(Arc::clone(self).run_health_checks());
+    }
+
+    // The configured percentile of the upstream's recent response times, clamped to the
+    // configured min and max.  Uses the max until the upstream has answered.
+    fn hedge_delay(&self, upstream: &Upstream) -> Option<Duration> {
+        let hedging_configuration = self.hedging_configuration.as_ref()?;
+
+        let min_delay = Duration::from_millis(hedging_configuration.min_delay_milliseconds());
+        let max_delay = Duration::from_millis(hedging_configuration.max_delay_milliseconds());
+
+        let delay = match upstream
+            .stats()
+            .response_time_percentile(hedging_configuration.latency_percentile())
+        {
+            None => max_delay,
+            Some(delay) => delay.max(min_delay).min(max_delay),
+        };
+
+        Some(delay)
+    }
+
+    // Sends the request to primary_upstream, and also to secondary_upstream if primary_upstream
+    // has not answered within hedge_delay.  Returns the first successful response and the
+    // number of upstreams that were tried.
+    async fn make_hedged_upstream_request(
+        &self,
+        primary_upstream: &Upstream,
+        secondary_upstream: &Upstream,
+        hedge_delay: Duration,
+        request_message: &Message,
+        request_buffer: &[u8],
+    ) -> (Option<DOHResponse>, usize) {
+        let primary_start_time = Instant::now();
+        let primary_future = self.make_upstream_request_with_timeout(
+            primary_upstream,
+            request_message,
+            request_buffer.to_vec(),
+        );
+        tokio::pin!(primary_future);
+
+        if let Ok(doh_response) = tokio::time::timeout(hedge_delay, &mut primary_future).await {
+            return (doh_response, 1);
+        }
+
+        debug!(
+            "hedging request to upstream {} after {:?}",
+            secondary_upstream.name(),
+            hedge_delay
+        );
+        self.hedged_requests.fetch_add(1, AtomicOrdering::Relaxed);
+
+        let secondary_future = self.make_upstream_request_with_timeout(
+            secondary_upstream,
+            request_message,
+            request_buffer.to_vec(),
+        );
+        tokio::pin!(secondary_future);
+
+        let doh_response = tokio::select! {
+            doh_response = &mut primary_future => match doh_response {
+                Some(doh_response) => Some(doh_response),
+                None => secondary_future.await,
+            },
+            doh_response = &mut secondary_future => match doh_response {
+                Some(doh_response) => {
+                    self.record_abandoned_upstream_request(
+                        primary_upstream,
+                        primary_start_time.elapsed().max(hedge_delay),
+                    );
+                    Some(doh_response)
+                }
+                None => primary_future.await,
+            },
+        };
+
+        (doh_response, 2)
+    }
+
+    async fn make_upstream_requests(
+        &self,
+        upstreams: &[&Upstream],
+        request_message: &Message,
+        request_buffer: &[u8],
+    ) -> Option<DOHResponse> {
+        let mut next_upstream_index = 0;
+
+        if upstreams.len() > 1 {
+            if let Some(hedge_delay) = self.hedge_delay(upstreams[0]) {
+                let (doh_response, upstreams_tried) = self
+                    .make_hedged_upstream_request(
+                        upstreams[0],
+                        upstreams[1],
+                        hedge_delay,
+                        request_message,
+                        request_buffer,
+                    )
+                    .await;
+                if doh_response.is_some() {
+                    return doh_response;
+                }
+                next_upstream_index = upstreams_tried;
+            }
+        }
+
+        for upstream in &upstreams[next_upstream_index..] {
+            if let Some(doh_response) = self
+                .make_upstream_request_with_timeout(
+                    upstream,
+                    request_message,
+                    request_buffer.to_vec(),
+                )
+                .await
+            {
+                return Some(doh_response);
+            }
+        }
+
+        None
+    }
+
+    // Exponential backoff starting at initial_backoff_milliseconds, capped at
+    // max_backoff_milliseconds, with a random delay between half and all of that.
+    fn retry_backoff_duration(retry_configuration: &RetryConfiguration, retry: u32) -> Duration {
+        let backoff_milliseconds = retry_configuration
+            .initial_backoff_milliseconds()
+            .saturating_mul(1u64.checked_shl(retry - 1).unwrap_or(u64::MAX))
+            .min(retry_configuration.max_backoff_milliseconds());
+
+        let jittered_backoff_milliseconds = if backoff_milliseconds == 0 {
+            0
+        } else {
+            rand::thread_rng().gen_range(backoff_milliseconds / 2, backoff_milliseconds + 1)
+        };
+
+        Duration::from_millis(jittered_backoff_milliseconds)
+    }
+
+    pub async fn make_doh_request(
+        &self,
+        request_message: &Message,
+        forwarding_rule: Option<&ForwardingRule>,
+    ) -> Result<DOHResponse, Box<dyn Error>> {
+        let mut doh_request_message = request_message.clone();
+        doh_request_message.set_id(0);
+        let request_buffer = utils::encode_dns_message(doh_request_message)?;
+
+        let _permit = self.request_queue.acquire().await?;
+
+        let upstreams = self.upstream_request_order(forwarding_rule);
+
+        let max_retries = match &self.retry_configuration {
+            None => 0,
+            Some(retry_configuration) => retry_configuration.max_retries(),
+        };
+
+        for retry in 0..=max_retries {
+            if let (true, Some(retry_configuration)) = (retry > 0, &self.retry_configuration) {
+                let backoff_duration = Self::retry_backoff_duration(retry_configuration, retry);
+                debug!("retry {} after backoff {:?}", retry, backoff_duration);
+                self.retries.fetch_add(1, AtomicOrdering::Relaxed);
+                tokio::time::delay_for(backoff_duration).await;
+            }
+
+            if let Some(doh_response) = self
+                .make_upstream_requests(&upstreams, request_message, &request_buffer)
+                .await
+            {
+                return Ok(doh_response);
+            }
+        }
+
+        Err(DOHRequestError::new(
+            DOHRequestErrorType::AllUpstreamsFailed,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use trust_dns_proto::rr::DNSClass;
+
+    use crate::doh::testutil::{self, TestDOHServer, TestPKI};
+
+    fn build_response_message(request_message: &Message) -> Message {
+        utils::decode_dns_message(testutil::build_response_buffer(
+            &utils::encode_dns_message(request_message.clone()).unwrap(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_response_message_matching() {
+        let request_message = testutil::build_request_message();
+
+        assert_eq!(
+            validate_response_message(
+                0,
+                &request_message,
+                &build_response_message(&request_message)
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_response_message_mismatches() {
+        let request_message = testutil::build_request_message();
+        let response_message = || build_response_message(&request_message);
+        // Response to a request with query instead of the request's query.
+        let response_message_for_query = |query: Query| {
+            let mut other_request_message = Message::new();
+            other_request_message
+                .set_op_code(OpCode::Query)
+                .add_query(query);
+            build_response_message(&other_request_message)
+        };
+        let name = request_message.queries()[0].name().clone();
+
+        let mut id_mismatch = response_message();
+        id_mismatch.set_id(1);
+        let mut not_response = response_message();
+        not_response.set_message_type(MessageType::Query);
+        let mut op_code_mismatch = response_message();
+        op_code_mismatch.set_op_code(OpCode::Status);
+        let mut extra_query = response_message();
+        extra_query.add_query(Query::query(name.clone(), RecordType::AAAA));
+        let mut class_mismatch = Query::query(name.clone(), RecordType::A);
+        class_mismatch.set_query_class(DNSClass::CH);
+
+        let tests = vec![
+            ("id", id_mismatch),
+            ("qr bit", not_response),
+            ("op code", op_code_mismatch),
+            ("query count", extra_query),
+            (
+                "query name",
+                response_message_for_query(Query::query(
+                    Name::from_ascii("example.net.").unwrap(),
+                    RecordType::A,
+                )),
+            ),
+            (
+                "query type",
+                response_message_for_query(Query::query(name, RecordType::AAAA)),
+            ),
+            ("query class", response_message_for_query(class_mismatch)),
+        ];
+
+        for (mismatch, response_message) in tests {
+            assert!(
+                validate_response_message(0, &request_message, &response_message).is_err(),
+                "{} mismatch",
+                mismatch
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_hedged_primary_loses_selection() {
+        let pki = TestPKI::new();
+        let servers = [
+            TestDOHServer::start(&pki).await,
+            TestDOHServer::start(&pki).await,
+        ];
+        let root_certificate_file = pki.root_certificate_file();
+        let upstream_fields = serde_json::json!({
+            "tls_configuration":
+                testutil::tls_configuration_json(&root_certificate_file, Vec::new()),
+        });
+        let doh_client = DOHClient::new(
+            testutil::build_client_configuration_with_upstreams(
+                vec![
+                    testutil::upstream_configuration_json(
+                        "0",
+                        &servers[0].url(),
+                        upstream_fields.clone(),
+                    ),
+                    testutil::upstream_configuration_json("1", &servers[1].url(), upstream_fields),
+                ],
+                serde_json::json!({
+                    "latency_selection_configuration": {
+                        "smoothing_factor": 0.5,
+                        "error_penalty_milliseconds": 1000,
+                        "exploration_interval_requests": 0,
+                    },
+                    "hedging_configuration": {
+                        "latency_percentile": 95.0,
+                        "min_delay_milliseconds": 100,
+                        "max_delay_milliseconds": 100,
+                    },
+                }),
+            ),
+            Metrics::new(),
+        )
+        .unwrap();
+        let request_message = testutil::build_request_message();
+        let selected_upstream_index = |doh_client: &DOHClient| -> usize {
+            doh_client.upstreams_by_score()[0].0.name().parse().unwrap()
+        };
+
+        // Both upstreams get tried while they are fast.
+        for _ in 0..4 {
+            doh_client
+                .make_doh_request(&request_message, None)
+                .await
+                .unwrap();
+        }
+
+        let slow_index = selected_upstream_index(&doh_client);
+        let fast_index = 1 - slow_index;
+        servers[slow_index].set_response_delay(Duration::from_secs(2));
+
+        doh_client
+            .make_doh_request(&request_message, None)
+            .await
+            .unwrap();
+        assert_eq!(doh_client.hedged_requests.load(AtomicOrdering::Relaxed), 1);
+        assert_eq!(selected_upstream_index(&doh_client), fast_index);
+
+        // Later requests go straight to the fast upstream.
+        let slow_requests = servers[slow_index].https_requests();
+        doh_client
+            .make_doh_request(&request_message, None)
+            .await
+            .unwrap();
+        assert_eq!(servers[slow_index].https_requests(), slow_requests);
+    }
+
+    #[cfg(feature = "quic")]
+    mod http3 {
+        use super::*;
+
+        use crate::doh::testutil::TestFile;
+
+        fn build_doh_client(pki: &TestPKI, server: &TestDOHServer) -> (DOHClient, TestFile) {
+            let root_certificate_file = pki.root_certificate_file();
+            let client_configuration = testutil::build_client_configuration(
+                &server.url(),
+                serde_json::json!({
+                    "tls_configuration":
+                        testutil::tls_configuration_json(&root_certificate_file, Vec::new()),
+                    "http3_configuration": {
+                        "connect_timeout_milliseconds": 500,
+                        "request_timeout_milliseconds": 1000,
+                        "fallback_retry_seconds": 1,
+                    },
+                }),
+            );
+
+            (
+                DOHClient::new(client_configuration, Metrics::new()).unwrap(),
+                root_certificate_file,
+            )
+        }
+
+        fn counter_value(doh_client: &DOHClient, counter_metric_type: CounterMetricType) -> u64 {
+            doh_client
+                .metrics
+                .counter_metric(counter_metric_type)
+                .value()
+        }
+
+        #[tokio::test]
+        async fn http3_request() {
+            let pki = TestPKI::new();
+            let server = TestDOHServer::start(&pki).await;
+            server.start_http3(&pki).await;
+            let (doh_client, _root_certificate_file) = build_doh_client(&pki, &server);
+
+            doh_client
+                .make_doh_request(&testutil::build_request_message(), None)
+                .await
+                .unwrap();
+
+            assert_eq!(server.http3_requests(), 1);
+            assert_eq!(server.https_requests(), 0);
+            assert_eq!(
+                counter_value(&doh_client, CounterMetricType::UpstreamHTTP3Responses),
+                1
+            );
+        }
 
-        let body = response.bytes().await?;
+        #[tokio::test]
+        async fn http3_fallback_and_retry() {
+            let pki = TestPKI::new();
+            // HTTP/3 is not started, so the first request falls back to HTTP/2.
+            let server = TestDOHServer::start(&pki).await;
+            let (doh_client, _root_certificate_file) = build_doh_client(&pki, &server);
+            let request_message = testutil::build_request_message();
 
-        let body_vec = body.bytes().to_vec();
-        Ok(body_vec)
+            doh_client
+                .make_doh_request(&request_message, None)
+                .await
+                .unwrap();
+            assert_eq!(server.https_requests(), 1);
+            assert_eq!(
+                counter_value(&doh_client, CounterMetricType::UpstreamHTTP3Fallbacks),
+                1
+            );
+
+            // HTTP/2 is used until fallback_retry_seconds pass.
+            server.start_http3(&pki).await;
+            doh_client
+                .make_doh_request(&request_message, None)
+                .await
+                .unwrap();
+            assert_eq!(server.https_requests(), 2);
+            assert_eq!(server.http3_requests(), 0);
+
+            tokio::time::delay_for(Duration::from_millis(1100)).await;
+            doh_client
+                .make_doh_request(&request_message, None)
+                .await
+                .unwrap();
+            assert_eq!(server.https_requests(), 2);
+            assert_eq!(server.http3_requests(), 1);
+            assert_eq!(
+                counter_value(&doh_client, CounterMetricType::UpstreamHTTP3Fallbacks),
+                1
+            );
+            assert_eq!(
+                counter_value(&doh_client, CounterMetricType::UpstreamHTTP3Responses),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn http3_error_response_does_not_fall_back() {
+            let pki = TestPKI::new();
+            let server = TestDOHServer::start(&pki).await;
+            server.start_http3(&pki).await;
+            server.set_response_status(500);
+            let (doh_client, _root_certificate_file) = build_doh_client(&pki, &server);
+
+            assert!(doh_client
+                .make_doh_request(&testutil::build_request_message(), None)
+                .await
+                .is_err());
+
+            assert_eq!(server.http3_requests(), 1);
+            assert_eq!(server.https_requests(), 0);
+            assert_eq!(
+                counter_value(&doh_client, CounterMetricType::UpstreamHTTP3Fallbacks),
+                0
+            );
+        }
     }
 }
@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::fmt;
 
 use log::info;
 use serde_derive::Deserialize;
@@ -68,13 +69,74 @@ impl ReverseDomainConfiguration {
     }
 }
 
+// RFC 8767 serve-stale.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServeStaleConfiguration {
+    stale_window_seconds: u64,
+    stale_ttl_seconds: u32,
+}
+
+impl ServeStaleConfiguration {
+    // How long expired entries are kept after their TTL runs out.
+    pub fn stale_window_seconds(&self) -> u64 {
+        self.stale_window_seconds
+    }
+
+    // TTL of records in stale responses, RFC 8767 recommends 30.
+    pub fn stale_ttl_seconds(&self) -> u32 {
+        self.stale_ttl_seconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrefetchConfiguration {
+    min_hits: u64,
+    remaining_ttl_percent: u32,
+}
+
+impl PrefetchConfiguration {
+    // Entries are prefetched once they have had at least min_hits cache hits and less than
+    // remaining_ttl_percent of their TTL remains.
+    pub fn min_hits(&self) -> u64 {
+        self.min_hits
+    }
+
+    pub fn remaining_ttl_percent(&self) -> u32 {
+        self.remaining_ttl_percent
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheSnapshotConfiguration {
+    file_path: String,
+    snapshot_interval_seconds: u64,
+}
+
+impl CacheSnapshotConfiguration {
+    pub fn file_path(&self) -> &String {
+        &self.file_path
+    }
+
+    // The snapshot is also written on shutdown.
+    pub fn snapshot_interval_seconds(&self) -> u64 {
+        self.snapshot_interval_seconds
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CacheConfiguration {
     max_size: usize,
     max_purges_per_timer_pop: usize,
+    shards: Option<usize>,
+    serve_stale_configuration: Option<ServeStaleConfiguration>,
+    prefetch_configuration: Option<PrefetchConfiguration>,
+    cache_snapshot_configuration: Option<CacheSnapshotConfiguration>,
 }
 
 impl CacheConfiguration {
+    // Split evenly between the shards.  Keys do not hash evenly, so a full shard evicts entries
+    // while others have room: with 16 shards a 20000 entry cache first evicts at about 95% full,
+    // and smaller caches earlier.
     pub fn max_size(&self) -> usize {
         self.max_size
     }
@@ -82,33 +144,479 @@ impl CacheConfiguration {
     pub fn max_purges_per_timer_pop(&self) -> usize {
         self.max_purges_per_timer_pop
     }
+
+    pub fn shards(&self) -> usize {
+        self.shards.unwrap_or(16)
+    }
+
+    pub fn serve_stale_configuration(&self) -> Option<&ServeStaleConfiguration> {
+        self.serve_stale_configuration.as_ref()
+    }
+
+    pub fn prefetch_configuration(&self) -> Option<&PrefetchConfiguration> {
+        self.prefetch_configuration.as_ref()
+    }
+
+    pub fn cache_snapshot_configuration(&self) -> Option<&CacheSnapshotConfiguration> {
+        self.cache_snapshot_configuration.as_ref()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamType {
+    DOH,
+    DOT,
+    UDP,
+    ODOH,
+    DOQ,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DOHRequestMethod {
+    Get,
+    Post,
 }
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct ClientConfiguration {
-    remote_url: String,
+pub struct TLSConfiguration {
+    root_certificate_files: Option<Vec<String>>,
+    spki_sha256_pins: Option<Vec<String>>,
+    client_certificate_file: Option<String>,
+    client_private_key_file: Option<String>,
+}
+
+impl TLSConfiguration {
+    pub fn root_certificate_files(&self) -> &[String] {
+        match &self.root_certificate_files {
+            None => &[],
+            Some(root_certificate_files) => root_certificate_files,
+        }
+    }
+
+    pub fn spki_sha256_pins(&self) -> &[String] {
+        match &self.spki_sha256_pins {
+            None => &[],
+            Some(spki_sha256_pins) => spki_sha256_pins,
+        }
+    }
+
+    pub fn client_certificate_file(&self) -> Option<&String> {
+        self.client_certificate_file.as_ref()
+    }
+
+    pub fn client_private_key_file(&self) -> Option<&String> {
+        self.client_private_key_file.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ODOHConfiguration {
+    proxy_url: String,
+    configs_url: Option<String>,
+    config_refresh_seconds: u64,
+}
+
+impl ODOHConfiguration {
+    pub fn proxy_url(&self) -> &String {
+        &self.proxy_url
+    }
+
+    // Defaults to the well known path on the target when not set.
+    pub fn configs_url(&self) -> Option<&String> {
+        self.configs_url.as_ref()
+    }
+
+    pub fn config_refresh_seconds(&self) -> u64 {
+        self.config_refresh_seconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HTTP3Configuration {
+    connect_timeout_milliseconds: u64,
+    request_timeout_milliseconds: u64,
+    fallback_retry_seconds: u64,
+}
+
+impl HTTP3Configuration {
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    pub fn connect_timeout_milliseconds(&self) -> u64 {
+        self.connect_timeout_milliseconds
+    }
+
+    // Must be less than the upstream request_timeout_seconds to leave time to fall back to
+    // HTTP/2, checked by read_configuration.
+    pub fn request_timeout_milliseconds(&self) -> u64 {
+        self.request_timeout_milliseconds
+    }
+
+    // How long to use HTTP/2 after an HTTP/3 failure before trying HTTP/3 again.
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    pub fn fallback_retry_seconds(&self) -> u64 {
+        self.fallback_retry_seconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfiguration {
+    name: String,
+    upstream_type: Option<UpstreamType>,
+    url: String,
+    request_method: Option<DOHRequestMethod>,
     request_timeout_seconds: u64,
-    max_outstanding_requests: usize,
+    use_by_default: Option<bool>,
+    bootstrap_ip_addresses: Option<Vec<String>>,
+    tls_configuration: Option<TLSConfiguration>,
+    edns_padding: Option<bool>,
+    odoh_configuration: Option<ODOHConfiguration>,
+    http3_configuration: Option<HTTP3Configuration>,
 }
 
-impl ClientConfiguration {
-    pub fn remote_url(&self) -> &String {
-        &self.remote_url
+impl UpstreamConfiguration {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn upstream_type(&self) -> UpstreamType {
+        self.upstream_type.unwrap_or(UpstreamType::DOH)
+    }
+
+    pub fn url(&self) -> &String {
+        &self.url
+    }
+
+    pub fn request_method(&self) -> DOHRequestMethod {
+        self.request_method.unwrap_or(DOHRequestMethod::Post)
+    }
+
+    pub fn request_timeout_seconds(&self) -> u64 {
+        self.request_timeout_seconds
+    }
+
+    // Upstreams that are not used by default only receive requests from forwarding rules.
+    pub fn use_by_default(&self) -> bool {
+        self.use_by_default.unwrap_or(true)
+    }
+
+    pub fn bootstrap_ip_addresses(&self) -> &[String] {
+        match &self.bootstrap_ip_addresses {
+            None => &[],
+            Some(bootstrap_ip_addresses) => bootstrap_ip_addresses,
+        }
+    }
+
+    pub fn tls_configuration(&self) -> Option<&TLSConfiguration> {
+        self.tls_configuration.as_ref()
+    }
+
+    pub fn edns_padding(&self) -> bool {
+        self.edns_padding.unwrap_or(false)
+    }
+
+    pub fn odoh_configuration(&self) -> Option<&ODOHConfiguration> {
+        self.odoh_configuration.as_ref()
+    }
+
+    // Only used by doh upstreams.
+    pub fn http3_configuration(&self) -> Option<&HTTP3Configuration> {
+        self.http3_configuration.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BootstrapConfiguration {
+    dns_server_url: String,
+    request_timeout_seconds: u64,
+    refresh_interval_seconds: u64,
+}
+
+impl BootstrapConfiguration {
+    pub fn dns_server_url(&self) -> &String {
+        &self.dns_server_url
     }
 
     pub fn request_timeout_seconds(&self) -> u64 {
         self.request_timeout_seconds
     }
 
+    pub fn refresh_interval_seconds(&self) -> u64 {
+        self.refresh_interval_seconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LatencySelectionConfiguration {
+    smoothing_factor: f64,
+    error_penalty_milliseconds: u64,
+    exploration_interval_requests: u64,
+}
+
+impl LatencySelectionConfiguration {
+    pub fn smoothing_factor(&self) -> f64 {
+        self.smoothing_factor
+    }
+
+    pub fn error_penalty_milliseconds(&self) -> u64 {
+        self.error_penalty_milliseconds
+    }
+
+    pub fn exploration_interval_requests(&self) -> u64 {
+        self.exploration_interval_requests
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestQueueConfiguration {
+    max_queue_size: usize,
+    max_wait_milliseconds: u64,
+}
+
+impl RequestQueueConfiguration {
+    pub fn max_queue_size(&self) -> usize {
+        self.max_queue_size
+    }
+
+    pub fn max_wait_milliseconds(&self) -> u64 {
+        self.max_wait_milliseconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfiguration {
+    max_retries: u32,
+    initial_backoff_milliseconds: u64,
+    max_backoff_milliseconds: u64,
+}
+
+impl RetryConfiguration {
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn initial_backoff_milliseconds(&self) -> u64 {
+        self.initial_backoff_milliseconds
+    }
+
+    pub fn max_backoff_milliseconds(&self) -> u64 {
+        self.max_backoff_milliseconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HedgingConfiguration {
+    latency_percentile: f64,
+    min_delay_milliseconds: u64,
+    max_delay_milliseconds: u64,
+}
+
+impl HedgingConfiguration {
+    pub fn latency_percentile(&self) -> f64 {
+        self.latency_percentile
+    }
+
+    pub fn min_delay_milliseconds(&self) -> u64 {
+        self.min_delay_milliseconds
+    }
+
+    pub fn max_delay_milliseconds(&self) -> u64 {
+        self.max_delay_milliseconds
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckConfiguration {
+    consecutive_failure_threshold: u32,
+    probe_timeout_seconds: Option<u64>,
+}
+
+impl HealthCheckConfiguration {
+    pub fn consecutive_failure_threshold(&self) -> u32 {
+        self.consecutive_failure_threshold
+    }
+
+    pub fn probe_timeout_seconds(&self) -> u64 {
+        self.probe_timeout_seconds.unwrap_or(2)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum EgressProxyType {
+    #[serde(rename = "http_connect")]
+    HTTPConnect,
+    #[serde(rename = "socks5")]
+    SOCKS5,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct EgressProxyConfiguration {
+    proxy_type: EgressProxyType,
+    proxy_address: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl EgressProxyConfiguration {
+    pub fn proxy_type(&self) -> EgressProxyType {
+        self.proxy_type
+    }
+
+    // host:port of the proxy.
+    pub fn proxy_address(&self) -> &String {
+        &self.proxy_address
+    }
+
+    pub fn username(&self) -> Option<&String> {
+        self.username.as_ref()
+    }
+
+    pub fn password(&self) -> Option<&String> {
+        self.password.as_ref()
+    }
+}
+
+// Written by hand so read_configuration does not log the proxy password.
+impl fmt::Debug for EgressProxyConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EgressProxyConfiguration")
+            .field("proxy_type", &self.proxy_type)
+            .field("proxy_address", &self.proxy_address)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfiguration {
+    #[serde(default)]
+    upstream_configurations: Vec<UpstreamConfiguration>,
+    // Single upstream configuration from before upstream_configurations, still accepted.
+    remote_url: Option<String>,
+    request_timeout_seconds: Option<u64>,
+    latency_selection_configuration: Option<LatencySelectionConfiguration>,
+    bootstrap_configuration: Option<BootstrapConfiguration>,
+    max_outstanding_requests: usize,
+    request_queue_configuration: Option<RequestQueueConfiguration>,
+    retry_configuration: Option<RetryConfiguration>,
+    hedging_configuration: Option<HedgingConfiguration>,
+    health_check_configuration: Option<HealthCheckConfiguration>,
+    egress_proxy_configuration: Option<EgressProxyConfiguration>,
+}
+
+impl ClientConfiguration {
+    pub fn upstream_configurations(&self) -> &Vec<UpstreamConfiguration> {
+        &self.upstream_configurations
+    }
+
+    pub fn latency_selection_configuration(&self) -> Option<&LatencySelectionConfiguration> {
+        self.latency_selection_configuration.as_ref()
+    }
+
+    pub fn bootstrap_configuration(&self) -> Option<&BootstrapConfiguration> {
+        self.bootstrap_configuration.as_ref()
+    }
+
     pub fn max_outstanding_requests(&self) -> usize {
         self.max_outstanding_requests
     }
+
+    pub fn request_queue_configuration(&self) -> Option<&RequestQueueConfiguration> {
+        self.request_queue_configuration.as_ref()
+    }
+
+    pub fn retry_configuration(&self) -> Option<&RetryConfiguration> {
+        self.retry_configuration.as_ref()
+    }
+
+    pub fn hedging_configuration(&self) -> Option<&HedgingConfiguration> {
+        self.hedging_configuration.as_ref()
+    }
+
+    pub fn health_check_configuration(&self) -> Option<&HealthCheckConfiguration> {
+        self.health_check_configuration.as_ref()
+    }
+
+    pub fn egress_proxy_configuration(&self) -> Option<&EgressProxyConfiguration> {
+        self.egress_proxy_configuration.as_ref()
+    }
+
+    // Converts a remote_url configuration to a single DoH upstream.
+    fn convert_remote_url(&mut self) -> Result<(), Box<dyn Error>> {
+        let remote_url = match self.remote_url.take() {
+            None => return Ok(()),
+            Some(remote_url) => remote_url,
+        };
+
+        if !self.upstream_configurations.is_empty() {
+            return Err("remote_url and upstream_configurations cannot both be set".into());
+        }
+
+        let request_timeout_seconds = self
+            .request_timeout_seconds
+            .ok_or("remote_url requires request_timeout_seconds")?;
+
+        self.upstream_configurations.push(UpstreamConfiguration {
+            name: "remote_url".to_string(),
+            upstream_type: None,
+            url: remote_url,
+            request_method: None,
+            request_timeout_seconds,
+            use_by_default: None,
+            bootstrap_ip_addresses: None,
+            tls_configuration: None,
+            edns_padding: None,
+            odoh_configuration: None,
+            http3_configuration: None,
+        });
+
+        Ok(())
+    }
+
+    fn validate_upstream_configurations(&self) -> Result<(), Box<dyn Error>> {
+        for upstream_configuration in &self.upstream_configurations {
+            if let Some(http3_configuration) = upstream_configuration.http3_configuration() {
+                if http3_configuration.request_timeout_milliseconds()
+                    >= upstream_configuration.request_timeout_seconds() * 1000
+                {
+                    return Err(format!(
+                        "upstream {} http3 request_timeout_milliseconds must be less than request_timeout_seconds",
+                        upstream_configuration.name()
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardingRuleConfiguration {
+    domain_suffix: String,
+    upstream_name: String,
+}
+
+impl ForwardingRuleConfiguration {
+    pub fn domain_suffix(&self) -> &String {
+        &self.domain_suffix
+    }
+
+    pub fn upstream_name(&self) -> &String {
+        &self.upstream_name
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ProxyConfiguration {
     clamp_min_ttl_seconds: u32,
     clamp_max_ttl_seconds: u32,
+    negative_clamp_min_ttl_seconds: Option<u32>,
+    negative_clamp_max_ttl_seconds: Option<u32>,
 }
 
 impl ProxyConfiguration {
@@ -119,6 +627,18 @@ impl ProxyConfiguration {
     pub fn clamp_max_ttl_seconds(&self) -> u32 {
         self.clamp_max_ttl_seconds
     }
+
+    // Used for NXDOMAIN and NODATA responses, defaults to clamp_min_ttl_seconds.
+    pub fn negative_clamp_min_ttl_seconds(&self) -> u32 {
+        self.negative_clamp_min_ttl_seconds
+            .unwrap_or(self.clamp_min_ttl_seconds)
+    }
+
+    // Used for NXDOMAIN and NODATA responses, defaults to clamp_max_ttl_seconds.
+    pub fn negative_clamp_max_ttl_seconds(&self) -> u32 {
+        self.negative_clamp_max_ttl_seconds
+            .unwrap_or(self.clamp_max_ttl_seconds)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -128,6 +648,8 @@ pub struct Configuration {
     reverse_domain_configurations: Vec<ReverseDomainConfiguration>,
     cache_configuration: CacheConfiguration,
     client_configuration: ClientConfiguration,
+    #[serde(default)]
+    forwarding_rule_configurations: Vec<ForwardingRuleConfiguration>,
     proxy_configuration: ProxyConfiguration,
     timer_interval_seconds: u64,
 }
@@ -153,6 +675,10 @@ impl Configuration {
         &self.client_configuration
     }
 
+    pub fn forwarding_rule_configurations(&self) -> &Vec<ForwardingRuleConfiguration> {
+        &self.forwarding_rule_configurations
+    }
+
     pub fn proxy_configuration(&self) -> &ProxyConfiguration {
         &self.proxy_configuration
     }
@@ -171,9 +697,63 @@ pub async fn read_configuration(config_file: String) -> Result<Configuration, Bo
 
     file.read_to_end(&mut file_contents).await?;
 
-    let configuration: Configuration = ::serde_json::from_slice(&file_contents)?;
+    let mut configuration: Configuration = ::serde_json::from_slice(&file_contents)?;
+
+    configuration.client_configuration.convert_remote_url()?;
+
+    configuration
+        .client_configuration
+        .validate_upstream_configurations()?;
 
     info!("read_configuration configuration\n{:#?}", configuration);
 
     Ok(configuration)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn egress_proxy_configuration_debug_redacts_password() {
+        let egress_proxy_configuration: EgressProxyConfiguration =
+            serde_json::from_value(serde_json::json!({
+                "proxy_type": "socks5",
+                "proxy_address": "127.0.0.1:1080",
+                "username": "proxyuser",
+                "password": "secret-password",
+            }))
+            .unwrap();
+
+        let debug_string = format!("{:?}", egress_proxy_configuration);
+        assert!(!debug_string.contains("secret-password"));
+        assert!(debug_string.contains(r#"password: Some("***")"#));
+        assert!(debug_string.contains("proxyuser"));
+    }
+
+    #[test]
+    fn http3_request_timeout_must_be_less_than_upstream_request_timeout() {
+        let validate = |http3_request_timeout_milliseconds| {
+            let client_configuration: ClientConfiguration =
+                serde_json::from_value(serde_json::json!({
+                    "upstream_configurations": [{
+                        "name": "test",
+                        "url": "https://dns.example/dns-query",
+                        "request_timeout_seconds": 5,
+                        "http3_configuration": {
+                            "connect_timeout_milliseconds": 500,
+                            "request_timeout_milliseconds": http3_request_timeout_milliseconds,
+                            "fallback_retry_seconds": 60,
+                        },
+                    }],
+                    "max_outstanding_requests": 10,
+                }))
+                .unwrap();
+            client_configuration.validate_upstream_configurations()
+        };
+
+        assert!(validate(4999).is_ok());
+        assert!(validate(5000).is_err());
+        assert!(validate(10000).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::Mutex;
+
+pub trait SlotConnection {
+    fn closed(&self) -> bool;
+}
+
+// Holds one shared upstream connection, reconnecting once it closes.  The lock is held while
+// connecting so concurrent requests wait for one new connection instead of each opening one.
+pub struct ConnectionSlot<C> {
+    protocol: &'static str,
+    connection: Mutex<Option<Arc<C>>>,
+}
+
+impl<C: SlotConnection> ConnectionSlot<C> {
+    pub fn new(protocol: &'static str) -> Self {
+        ConnectionSlot {
+            protocol,
+            connection: Mutex::new(None),
+        }
+    }
+
+    // Returns the connection and whether it was newly created.
+    pub async fn get_connection<E, Connect, ConnectFuture>(
+        &self,
+        connect: Connect,
+    ) -> Result<(Arc<C>, bool), E>
+    where
+        Connect: FnOnce() -> ConnectFuture,
+        ConnectFuture: Future<Output = Result<Arc<C>, E>>,
+    {
+        let mut connection_option = self.connection.lock().await;
+
+        if let Some(connection) = connection_option.as_ref() {
+            if !connection.closed() {
+                return Ok((Arc::clone(connection), false));
+            }
+        }
+
+        let connection = connect().await?;
+        *connection_option = Some(Arc::clone(&connection));
+
+        Ok((connection, true))
+    }
+
+    // Makes the request on the shared connection.  Servers close idle connections, so a request
+    // that fails on a reused connection which has since closed is retried once on a new one.
+    pub async fn make_request<T, E, Connect, ConnectFuture, Request, RequestFuture>(
+        &self,
+        connect: Connect,
+        request: Request,
+    ) -> Result<T, E>
+    where
+        E: Display,
+        Connect: Fn() -> ConnectFuture,
+        ConnectFuture: Future<Output = Result<Arc<C>, E>>,
+        Request: Fn(Arc<C>) -> RequestFuture,
+        RequestFuture: Future<Output = Result<T, E>>,
+    {
+        let (connection, new_connection) = self.get_connection(&connect).await?;
+
+        // The error is dropped before retrying since it may not be Send.
+        match request(Arc::clone(&connection)).await {
+            Err(e) if new_connection || !connection.closed() => return Err(e),
+            Err(e) => debug!(
+                "{} request on reused connection failed {}",
+                self.protocol, e
+            ),
+            result => return result,
+        }
+
+        let (connection, _) = self.get_connection(&connect).await?;
+        request(connection).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct TestConnection {
+        id: usize,
+        closed: AtomicBool,
+    }
+
+    impl SlotConnection for TestConnection {
+        fn closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+    }
+
+    struct TestClient {
+        slot: ConnectionSlot<TestConnection>,
+        connect_count: AtomicUsize,
+    }
+
+    impl TestClient {
+        fn new() -> Self {
+            TestClient {
+                slot: ConnectionSlot::new("test"),
+                connect_count: AtomicUsize::new(0),
+            }
+        }
+
+        async fn connect(&self) -> Result<Arc<TestConnection>, String> {
+            Ok(Arc::new(TestConnection {
+                id: self.connect_count.fetch_add(1, Ordering::Relaxed),
+                closed: AtomicBool::new(false),
+            }))
+        }
+
+        async fn get_connection(&self) -> (Arc<TestConnection>, bool) {
+            self.slot.get_connection(|| self.connect()).await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn get_connection_reuses_until_closed() {
+        let client = TestClient::new();
+
+        let (connection, new_connection) = client.get_connection().await;
+        assert_eq!(connection.id, 0);
+        assert!(new_connection);
+
+        let (connection, new_connection) = client.get_connection().await;
+        assert_eq!(connection.id, 0);
+        assert!(!new_connection);
+
+        connection.closed.store(true, Ordering::Relaxed);
+
+        let (connection, new_connection) = client.get_connection().await;
+        assert_eq!(connection.id, 1);
+        assert!(new_connection);
+    }
+
+    #[tokio::test]
+    async fn make_request_retries_closed_reused_connection() {
+        let client = TestClient::new();
+        client.get_connection().await;
+
+        let request_ids = std::sync::Mutex::new(Vec::new());
+        let result: Result<usize, String> = client
+            .slot
+            .make_request(
+                || client.connect(),
+                |connection| {
+                    request_ids.lock().unwrap().push(connection.id);
+                    let result = if connection.id == 0 {
+                        connection.closed.store(true, Ordering::Relaxed);
+                        Err("connection closed".to_string())
+                    } else {
+                        Ok(connection.id)
+                    };
+                    async move { result }
+                },
+            )
+            .await;
+
+        assert_eq!(result, Ok(1));
+        assert_eq!(*request_ids.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn make_request_does_not_retry() {
+        let client = TestClient::new();
+
+        // A new connection is not retried.
+        let result: Result<(), String> = client
+            .slot
+            .make_request(
+                || client.connect(),
+                |connection| {
+                    connection.closed.store(true, Ordering::Relaxed);
+                    async { Err("connection closed".to_string()) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(client.connect_count.load(Ordering::Relaxed), 1);
+
+        client.get_connection().await;
+
+        // An error on a reused connection that is still open is not retried.
+        let request_count = AtomicUsize::new(0);
+        let result: Result<(), String> = client
+            .slot
+            .make_request(
+                || client.connect(),
+                |_| {
+                    request_count.fetch_add(1, Ordering::Relaxed);
+                    async { Err("request failed".to_string()) }
+                },
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(request_count.load(Ordering::Relaxed), 1);
+        assert_eq!(client.connect_count.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::service::Service;
+use hyper::Uri;
+use log::debug;
+use tokio::net::TcpStream;
+
+use crate::doh::bootstrap::BootstrapResolver;
+use crate::doh::egressproxy::EgressProxy;
+
+// Establishes TCP connections to upstream servers using the BootstrapResolver, or through the
+// EgressProxy when one is configured.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    bootstrap_resolver: Arc<BootstrapResolver>,
+    egress_proxy: Option<Arc<EgressProxy>>,
+}
+
+impl UpstreamConnector {
+    pub fn new(
+        bootstrap_resolver: Arc<BootstrapResolver>,
+        egress_proxy: Option<Arc<EgressProxy>>,
+    ) -> Self {
+        UpstreamConnector {
+            bootstrap_resolver,
+            egress_proxy,
+        }
+    }
+
+    // QUIC runs over UDP, which cannot go through the TCP egress proxy.
+    #[cfg(feature = "quic")]
+    pub fn has_egress_proxy(&self) -> bool {
+        self.egress_proxy.is_some()
+    }
+
+    pub async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        self.bootstrap_resolver.resolve(host, port).await
+    }
+
+    pub async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let egress_proxy = match &self.egress_proxy {
+            None => return self.connect_direct(host, port).await,
+            Some(egress_proxy) => egress_proxy,
+        };
+
+        let mut tcp_stream = self
+            .connect_direct(egress_proxy.proxy_host(), egress_proxy.proxy_port())
+            .await
+            .map_err(|e| egress_proxy.map_proxy_connect_error(e))?;
+
+        egress_proxy
+            .connect_tunnel(&mut tcp_stream, host, port)
+            .await?;
+
+        Ok(tcp_stream)
+    }
+
+    async fn connect_direct(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let socket_addresses = self.resolve(host, port).await?;
+
+        let mut last_error = None;
+
+        for socket_address in socket_addresses {
+            match TcpStream::connect(socket_address).await {
+                Ok(tcp_stream) => {
+                    debug!("connected to {} at {}", host, socket_address);
+                    tcp_stream.set_nodelay(true)?;
+                    return Ok(tcp_stream);
+                }
+                Err(e) => {
+                    debug!("connect to {} at {} error {}", host, socket_address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {}", host),
+            )
+        }))
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let upstream_connector = self.clone();
+
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("uri has no host: {}", uri),
+                )
+            })?;
+
+            let port = match uri.port_u16() {
+                Some(port) => port,
+                None if uri.scheme_str() == Some("http") => 80,
+                None => 443,
+            };
+
+            // Uri hosts keep the brackets around ipv6 addresses.
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+
+            upstream_connector.connect(host, port).await
+        })
+    }
+}
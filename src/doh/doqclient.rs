@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::doh::config::TLSConfiguration;
+use crate::doh::connectionslot::{ConnectionSlot, SlotConnection};
+use crate::doh::connector::UpstreamConnector;
+use crate::doh::quic::{self, QUICError};
+
+const DEFAULT_DOQ_PORT: u16 = 853; // RFC 9250 section 4.1.1
+
+// RFC 9250 section 4.1.1
+const DOQ_ALPN: &[u8] = b"doq";
+
+// One 2-octet length prefixed DNS message per stream, RFC 9250 section 4.2.
+const MAX_DOQ_RESPONSE_LENGTH: usize = 2 + (u16::MAX as usize);
+
+// RFC 9250 section 4.3
+const DOQ_REQUEST_CANCELLED: u32 = 0x3;
+
+// Cancels the request's stream if the request is dropped before it completes, i.e. when
+// quic::run aborts it on an upstream request timeout, or fails.  Only the stream is cancelled,
+// so other requests on the connection are not affected.
+struct RequestStreams {
+    send_stream: quinn::SendStream,
+    recv_stream: quinn::RecvStream,
+    completed: bool,
+}
+
+impl Drop for RequestStreams {
+    fn drop(&mut self) {
+        if !self.completed {
+            debug!("cancelling abandoned doq request stream");
+            let _ = self.send_stream.reset(DOQ_REQUEST_CANCELLED.into());
+            let _ = self.recv_stream.stop(DOQ_REQUEST_CANCELLED.into());
+        }
+    }
+}
+
+struct DOQConnection {
+    _endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+}
+
+impl SlotConnection for DOQConnection {
+    fn closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+}
+
+impl DOQConnection {
+    async fn make_request(&self, request_buffer: &[u8]) -> Result<Vec<u8>, QUICError> {
+        if request_buffer.len() < 2 {
+            return Err("doq request buffer too short".into());
+        }
+
+        // RFC 9250 section 4.2.1: the message ID must be 0 since each query has its own stream.
+        let mut request_message = Vec::with_capacity(request_buffer.len() + 2);
+        request_message.extend_from_slice(&(request_buffer.len() as u16).to_be_bytes());
+        request_message.extend_from_slice(&[0, 0]);
+        request_message.extend_from_slice(&request_buffer[2..]);
+
+        let connection = self.connection.clone();
+
+        quic::run(async move {
+            let (send_stream, recv_stream) = connection.open_bi().await?;
+            let mut request_streams = RequestStreams {
+                send_stream,
+                recv_stream,
+                completed: false,
+            };
+
+            request_streams
+                .send_stream
+                .write_all(&request_message)
+                .await?;
+            request_streams.send_stream.finish()?;
+
+            let response_message = request_streams
+                .recv_stream
+                .read_to_end(MAX_DOQ_RESPONSE_LENGTH)
+                .await?;
+            request_streams.completed = true;
+
+            if response_message.len() < 2 {
+                return Err(format!("doq short response length {}", response_message.len()).into());
+            }
+
+            let response_length =
+                u16::from_be_bytes([response_message[0], response_message[1]]) as usize;
+            if response_length != response_message.len() - 2 {
+                return Err(format!(
+                    "doq response length {} does not match stream length {}",
+                    response_length,
+                    response_message.len() - 2
+                )
+                .into());
+            }
+
+            Ok(response_message[2..].to_vec())
+        })
+        .await
+    }
+}
+
+// RFC 9250 DNS over QUIC client.  Queries share one QUIC connection, each on its own stream.
+pub struct DOQClient {
+    server_name: String,
+    server_port: u16,
+    upstream_connector: UpstreamConnector,
+    client_config: quinn::ClientConfig,
+    connect_timeout: Duration,
+    connection_slot: ConnectionSlot<DOQConnection>,
+}
+
+impl DOQClient {
+    pub fn new(
+        url: &str,
+        tls_configuration: Option<&TLSConfiguration>,
+        connect_timeout: Duration,
+        upstream_connector: UpstreamConnector,
+    ) -> Result<Self, Box<dyn Error>> {
+        let url = url::Url::parse(url)?;
+
+        let server_name = url
+            .host_str()
+            .ok_or_else(|| format!("doq url has no host: {}", url))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let server_port = url.port().unwrap_or(DEFAULT_DOQ_PORT);
+
+        Ok(DOQClient {
+            server_name,
+            server_port,
+            upstream_connector,
+            client_config: quic::build_quic_client_config(
+                tls_configuration,
+                vec![DOQ_ALPN.to_vec()],
+            )?,
+            connect_timeout,
+            connection_slot: ConnectionSlot::new("doq"),
+        })
+    }
+
+    async fn connect(&self) -> Result<Arc<DOQConnection>, QUICError> {
+        let (endpoint, connection) = quic::connect(
+            &self.upstream_connector,
+            &self.client_config,
+            &self.server_name,
+            self.server_port,
+            self.connect_timeout,
+        )
+        .await?;
+
+        info!(
+            "connected to doq server {}:{}",
+            self.server_name, self.server_port
+        );
+
+        Ok(Arc::new(DOQConnection {
+            _endpoint: endpoint,
+            connection,
+        }))
+    }
+
+    pub async fn make_doq_request(
+        &self,
+        request_buffer: Vec<u8>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let request_buffer = &request_buffer;
+
+        let mut response_buffer = self
+            .connection_slot
+            .make_request(
+                || self.connect(),
+                |connection| async move { connection.make_request(request_buffer).await },
+            )
+            .await
+            .map_err(quic::map_quic_error)?;
+
+        if response_buffer.len() < 2 {
+            return Err(format!("doq short response length {}", response_buffer.len()).into());
+        }
+
+        // Restore the caller's message ID.
+        response_buffer[..2].copy_from_slice(&request_buffer[..2]);
+
+        Ok(response_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::doh::testutil::{self, TestPKI, TEST_DNS_NAME};
+    use crate::doh::utils;
+
+    type StopCodeSender = tokio::sync::oneshot::Sender<Option<u64>>;
+
+    // Leaves the first request unanswered and sends the error code the client stops its stream
+    // with, answers the others.
+    struct TestDOQServer {
+        address: SocketAddr,
+        connections: Arc<AtomicUsize>,
+        first_stream_stop_code: tokio::sync::oneshot::Receiver<Option<u64>>,
+    }
+
+    impl TestDOQServer {
+        async fn start(pki: &TestPKI) -> Self {
+            let endpoint = testutil::start_quic_server(
+                testutil::build_quic_server_config(&[&pki.leaf, &pki.intermediate], DOQ_ALPN),
+                0,
+            )
+            .await;
+            let address = endpoint.local_addr().unwrap();
+            let connections = Arc::new(AtomicUsize::new(0));
+            let (stop_code_sender, first_stream_stop_code) = tokio::sync::oneshot::channel();
+
+            let server_connections = Arc::clone(&connections);
+            let stop_code_sender = Arc::new(std::sync::Mutex::new(Some(stop_code_sender)));
+            quic::spawn(async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    server_connections.fetch_add(1, Ordering::SeqCst);
+                    quic::spawn(Self::serve_connection(
+                        incoming,
+                        Arc::clone(&stop_code_sender),
+                    ));
+                }
+            });
+
+            TestDOQServer {
+                address,
+                connections,
+                first_stream_stop_code,
+            }
+        }
+
+        async fn serve_connection(
+            incoming: quinn::Incoming,
+            stop_code_sender: Arc<std::sync::Mutex<Option<StopCodeSender>>>,
+        ) {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+
+            while let Ok((mut send_stream, mut recv_stream)) = connection.accept_bi().await {
+                let request_message = recv_stream.read_to_end(1024).await.unwrap();
+
+                let first_stream_stop_code_sender = stop_code_sender.lock().unwrap().take();
+                match first_stream_stop_code_sender {
+                    Some(stop_code_sender) => quic::spawn(async move {
+                        let stop_code = send_stream.stopped().await.unwrap();
+                        let _ = stop_code_sender.send(stop_code.map(u64::from));
+                    }),
+                    None => {
+                        let response_buffer =
+                            testutil::build_response_buffer(&request_message[2..]);
+                        let mut response_message =
+                            (response_buffer.len() as u16).to_be_bytes().to_vec();
+                        response_message.extend_from_slice(&response_buffer);
+                        send_stream.write_all(&response_message).await.unwrap();
+                        send_stream.finish().unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn abandoned_request_cancels_only_its_stream() {
+        let pki = TestPKI::new();
+        let server = TestDOQServer::start(&pki).await;
+
+        let url = format!("doq://{}:{}", TEST_DNS_NAME, server.address.port());
+        let root_certificate_file = pki.root_certificate_file();
+        let doq_client = DOQClient::new(
+            &url,
+            Some(&testutil::build_tls_configuration(&root_certificate_file)),
+            Duration::from_secs(5),
+            testutil::build_upstream_connector(&url),
+        )
+        .unwrap();
+
+        let request_buffer = utils::encode_dns_message(testutil::build_request_message()).unwrap();
+
+        // Dropped on the timeout, as DOHClient does on an upstream request timeout.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(200),
+            doq_client.make_doq_request(request_buffer.clone())
+        )
+        .await
+        .is_err());
+
+        assert_eq!(
+            server.first_stream_stop_code.await.unwrap(),
+            Some(u64::from(DOQ_REQUEST_CANCELLED))
+        );
+
+        let response_buffer = doq_client
+            .make_doq_request(request_buffer.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            response_buffer,
+            testutil::build_response_buffer(&request_buffer)
+        );
+        assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Arc;
+
+use futures::future::{AbortHandle, Abortable};
+use log::{debug, info, warn};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use crate::doh::client::{map_connection_error, DOHRequestError, DOHRequestErrorType};
+use crate::doh::config::TLSConfiguration;
+use crate::doh::connectionslot::{ConnectionSlot, SlotConnection};
+use crate::doh::connector::UpstreamConnector;
+use crate::doh::tls;
+use crate::doh::utils;
+
+const DEFAULT_DOT_PORT: u16 = 853; // RFC 7858 section 3.1
+
+type DOTStream = TlsStream<TcpStream>;
+
+type ResponseReceiver = oneshot::Receiver<Vec<u8>>;
+
+struct DOTConnection {
+    // None once closed.
+    writer: Arc<Mutex<Option<WriteHalf<DOTStream>>>>,
+    pending_requests: std::sync::Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    next_id: AtomicU16,
+    closed: AtomicBool,
+    reader_abort_handle: AbortHandle,
+}
+
+// Removes the pending request entry when the request completes or is dropped on timeout.
+struct PendingRequestGuard<'a> {
+    connection: &'a DOTConnection,
+    id: u16,
+}
+
+impl Drop for PendingRequestGuard<'_> {
+    fn drop(&mut self) {
+        self.connection
+            .pending_requests
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+// Closes the connection if a write is dropped before it completes, i.e. on an upstream request
+// timeout, since a partially written message breaks the length prefixed framing for every later
+// request on the connection.
+struct IncompleteWriteGuard<'a> {
+    connection: &'a DOTConnection,
+    completed: bool,
+}
+
+impl Drop for IncompleteWriteGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            debug!("closing dot connection after incomplete write");
+            self.connection.close();
+        }
+    }
+}
+
+impl SlotConnection for DOTConnection {
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl DOTConnection {
+    fn new(writer: WriteHalf<DOTStream>, reader_abort_handle: AbortHandle) -> Arc<Self> {
+        Arc::new(DOTConnection {
+            writer: Arc::new(Mutex::new(Some(writer))),
+            pending_requests: std::sync::Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(0),
+            closed: AtomicBool::new(false),
+            reader_abort_handle,
+        })
+    }
+
+    // The reader task and the writer each hold half of the stream, so both have to go for the
+    // socket to be closed.
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // Dropping the senders wakes up all waiting requests with an error.
+        self.pending_requests.lock().unwrap().clear();
+
+        self.reader_abort_handle.abort();
+
+        // The writer may be locked by a write in progress, so shut it down once that is done.
+        let writer = Arc::clone(&self.writer);
+        tokio::spawn(async move {
+            if let Some(mut writer) = writer.lock().await.take() {
+                if let Err(e) = writer.shutdown().await {
+                    debug!("dot shutdown error {}", e);
+                }
+            }
+        });
+    }
+
+    fn add_pending_request(
+        &self,
+    ) -> Result<(PendingRequestGuard<'_>, ResponseReceiver), Box<dyn Error>> {
+        let (sender, receiver) = oneshot::channel();
+
+        let mut pending_requests = self.pending_requests.lock().unwrap();
+
+        let id = (0..=u16::MAX)
+            .map(|_| self.next_id.fetch_add(1, Ordering::Relaxed))
+            .find(|id| !pending_requests.contains_key(id))
+            .ok_or_else(|| DOHRequestError::new(DOHRequestErrorType::TooManyOutstandingRequests))?;
+
+        pending_requests.insert(id, sender);
+
+        Ok((
+            PendingRequestGuard {
+                connection: self,
+                id,
+            },
+            receiver,
+        ))
+    }
+
+    async fn run_reader(self: Arc<Self>, mut reader: ReadHalf<DOTStream>) {
+        loop {
+            let buffer = match utils::read_tcp_message(&mut reader).await {
+                Err(e) => {
+                    debug!("dot read_tcp_message error {}", e);
+                    break;
+                }
+                Ok(buffer) => buffer,
+            };
+
+            if buffer.len() < 2 {
+                warn!("dot read short message length {}", buffer.len());
+                break;
+            }
+
+            let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+
+            let sender = self.pending_requests.lock().unwrap().remove(&id);
+            match sender {
+                None => debug!("dot response for unknown id {}", id),
+                Some(sender) => {
+                    let _ = sender.send(buffer);
+                }
+            }
+        }
+
+        self.close();
+    }
+
+    async fn make_request(&self, request_buffer: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if request_buffer.len() < 2 {
+            return Err("dot request buffer too short".into());
+        }
+
+        let (pending_request_guard, receiver) = self.add_pending_request()?;
+
+        // A request added after close would never get its response.
+        if self.closed() {
+            return Err(DOHRequestError::new(DOHRequestErrorType::ConnectionClosed));
+        }
+
+        // Responses are matched by message ID, so each in-flight request needs a unique one.
+        let mut request_buffer = request_buffer.to_vec();
+        request_buffer[..2].copy_from_slice(&pending_request_guard.id.to_be_bytes());
+
+        {
+            let mut writer_option = self.writer.lock().await;
+            let writer = writer_option
+                .as_mut()
+                .ok_or_else(|| DOHRequestError::new(DOHRequestErrorType::ConnectionClosed))?;
+            let mut incomplete_write_guard = IncompleteWriteGuard {
+                connection: self,
+                completed: false,
+            };
+            if let Err(e) = utils::write_tcp_message(writer, &request_buffer).await {
+                warn!("dot write_tcp_message error {}", e);
+                return Err(e.into());
+            }
+            incomplete_write_guard.completed = true;
+        }
+
+        let response_buffer = receiver
+            .await
+            .map_err(|_| DOHRequestError::new(DOHRequestErrorType::ConnectionClosed))?;
+
+        drop(pending_request_guard);
+
+        Ok(response_buffer)
+    }
+}
+
+pub struct DOTClient {
+    server_name: String,
+    server_port: u16,
+    upstream_connector: UpstreamConnector,
+    tls_connector: TlsConnector,
+    connection_slot: ConnectionSlot<DOTConnection>,
+}
+
+impl DOTClient {
+    pub fn new(
+        url: &str,
+        tls_configuration: Option<&TLSConfiguration>,
+        upstream_connector: UpstreamConnector,
+    ) -> Result<Self, Box<dyn Error>> {
+        let url = url::Url::parse(url)?;
+
+        let server_name = url
+            .host_str()
+            .ok_or_else(|| format!("dot url has no host: {}", url))?
+            .to_string();
+
+        DNSNameRef::try_from_ascii_str(&server_name)
+            .map_err(|_| format!("invalid dot server name: {}", server_name))?;
+
+        let server_port = url.port().unwrap_or(DEFAULT_DOT_PORT);
+
+        let tls_config = tls::build_tls_config(tls_configuration, vec![])?;
+
+        Ok(DOTClient {
+            server_name,
+            server_port,
+            upstream_connector,
+            tls_connector: TlsConnector::from(tls_config),
+            connection_slot: ConnectionSlot::new("dot"),
+        })
+    }
+
+    async fn connect(&self) -> Result<Arc<DOTConnection>, Box<dyn Error>> {
+        let tcp_stream = self
+            .upstream_connector
+            .connect(&self.server_name, self.server_port)
+            .await
+            .map_err(map_connection_error)?;
+
+        let dns_name_ref = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map_err(|_| format!("invalid dot server name: {}", self.server_name))?;
+
+        let tls_stream = self
+            .tls_connector
+            .connect(dns_name_ref, tcp_stream)
+            .await
+            .map_err(map_connection_error)?;
+
+        info!(
+            "connected to dot server {}:{}",
+            self.server_name, self.server_port
+        );
+
+        let (reader, writer) = tokio::io::split(tls_stream);
+
+        let (reader_abort_handle, reader_abort_registration) = AbortHandle::new_pair();
+
+        let connection = DOTConnection::new(writer, reader_abort_handle);
+
+        tokio::spawn(Abortable::new(
+            Arc::clone(&connection).run_reader(reader),
+            reader_abort_registration,
+        ));
+
+        Ok(connection)
+    }
+
+    pub async fn make_dot_request(
+        &self,
+        request_buffer: Vec<u8>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let request_buffer = &request_buffer;
+
+        let mut response_buffer = self
+            .connection_slot
+            .make_request(
+                || self.connect(),
+                |connection| async move { connection.make_request(request_buffer).await },
+            )
+            .await?;
+
+        // Restore the caller's message ID.
+        response_buffer[..2].copy_from_slice(&request_buffer[..2]);
+
+        Ok(response_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::doh::testutil::{self, TestPKI, TEST_DNS_NAME};
+
+    #[tokio::test]
+    async fn close_closes_socket() {
+        let pki = TestPKI::new();
+        let tls_acceptor = testutil::build_tls_acceptor(&pki, &[]);
+        let mut tcp_listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = tcp_listener.local_addr().unwrap().port();
+
+        // Reads until the client closes the connection.
+        let server = tokio::spawn(async move {
+            let (tcp_stream, _) = tcp_listener.accept().await.unwrap();
+            let mut tls_stream = tls_acceptor.accept(tcp_stream).await.unwrap();
+            let mut buffer = [0; 512];
+            while let Ok(length) = tls_stream.read(&mut buffer).await {
+                if length == 0 {
+                    break;
+                }
+            }
+        });
+
+        let url = format!("tls://{}:{}", TEST_DNS_NAME, port);
+        let root_certificate_file = pki.root_certificate_file();
+        let dot_client = DOTClient::new(
+            &url,
+            Some(&testutil::build_tls_configuration(&root_certificate_file)),
+            testutil::build_upstream_connector(&url),
+        )
+        .unwrap();
+
+        let (connection, _) = dot_client
+            .connection_slot
+            .get_connection(|| dot_client.connect())
+            .await
+            .unwrap();
+        connection.close();
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::doh::config::{EgressProxyConfiguration, EgressProxyType};
+use crate::doh::utils;
+
+const MAX_HTTP_CONNECT_RESPONSE_LENGTH: usize = 8_192;
+
+// RFC 1928 and RFC 1929
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_AUTH_METHOD_NONE: u8 = 0;
+const SOCKS5_AUTH_METHOD_USERNAME_PASSWORD: u8 = 2;
+const SOCKS5_USERNAME_PASSWORD_VERSION: u8 = 1;
+const SOCKS5_COMMAND_CONNECT: u8 = 1;
+const SOCKS5_ADDRESS_TYPE_IPV4: u8 = 1;
+const SOCKS5_ADDRESS_TYPE_DOMAIN_NAME: u8 = 3;
+const SOCKS5_ADDRESS_TYPE_IPV6: u8 = 4;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0;
+
+// Wrapped in the io::Error returned by UpstreamConnector so proxy failures can be told apart
+// from other connection errors.
+#[derive(Debug)]
+struct EgressProxyError {
+    message: String,
+}
+
+impl fmt::Display for EgressProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "egress proxy error: {}", self.message)
+    }
+}
+
+impl Error for EgressProxyError {}
+
+fn egress_proxy_error(message: String) -> std::io::Error {
+    // The message is not in the DOHRequestError this maps to, so log it here.
+    warn!("egress proxy error: {}", message);
+    std::io::Error::other(EgressProxyError { message })
+}
+
+fn protocol_error(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+pub fn is_egress_proxy_error(error: &(dyn Error + 'static)) -> bool {
+    utils::error_chain_contains(error, &|error| error.is::<EgressProxyError>())
+}
+
+fn socks5_reply_message(reply: u8) -> &'static str {
+    match reply {
+        1 => "general socks server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "ttl expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown reply",
+    }
+}
+
+// Tunnels upstream TCP connections through an HTTP CONNECT or SOCKS5 proxy.  Upstream host
+// names are sent to the proxy unresolved so the proxy does the name resolution.
+pub struct EgressProxy {
+    proxy_type: EgressProxyType,
+    proxy_host: String,
+    proxy_port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl EgressProxy {
+    pub fn new(
+        egress_proxy_configuration: &EgressProxyConfiguration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let proxy_address = egress_proxy_configuration.proxy_address();
+
+        let proxy_url = url::Url::parse(&format!("tcp://{}", proxy_address))
+            .map_err(|e| format!("invalid proxy_address {}: {}", proxy_address, e))?;
+
+        let proxy_host = proxy_url
+            .host_str()
+            .ok_or_else(|| format!("proxy_address has no host: {}", proxy_address))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let proxy_port = proxy_url
+            .port()
+            .ok_or_else(|| format!("proxy_address has no port: {}", proxy_address))?;
+
+        let credentials = match (
+            egress_proxy_configuration.username(),
+            egress_proxy_configuration.password(),
+        ) {
+            (None, None) => None,
+            (None, Some(_)) => return Err("egress proxy password set without username".into()),
+            (Some(username), password) => {
+                let password = password.cloned().unwrap_or_default();
+                if egress_proxy_configuration.proxy_type() == EgressProxyType::SOCKS5
+                    && (username.is_empty() || username.len() > 255 || password.len() > 255)
+                {
+                    return Err("socks5 username and password must be 1 to 255 bytes".into());
+                }
+                Some((username.clone(), password))
+            }
+        };
+
+        Ok(EgressProxy {
+            proxy_type: egress_proxy_configuration.proxy_type(),
+            proxy_host,
+            proxy_port,
+            credentials,
+        })
+    }
+
+    pub fn proxy_host(&self) -> &str {
+        &self.proxy_host
+    }
+
+    pub fn proxy_port(&self) -> u16 {
+        self.proxy_port
+    }
+
+    // Asks the proxy connected on tcp_stream to open a tunnel to host:port.
+    pub async fn connect_tunnel(
+        &self,
+        tcp_stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<()> {
+        let result = match self.proxy_type {
+            EgressProxyType::HTTPConnect => self.http_connect(tcp_stream, host, port).await,
+            EgressProxyType::SOCKS5 => self.socks5_connect(tcp_stream, host, port).await,
+        };
+
+        match result {
+            Ok(()) => {
+                debug!(
+                    "connected to {}:{} through proxy {}:{}",
+                    host, port, self.proxy_host, self.proxy_port
+                );
+                Ok(())
+            }
+            Err(e) => Err(egress_proxy_error(format!(
+                "tunnel to {}:{} through proxy {}:{} failed: {}",
+                host, port, self.proxy_host, self.proxy_port, e
+            ))),
+        }
+    }
+
+    // Wraps an error connecting to the proxy itself.
+    pub fn map_proxy_connect_error(&self, error: std::io::Error) -> std::io::Error {
+        egress_proxy_error(format!(
+            "connect to proxy {}:{} failed: {}",
+            self.proxy_host, self.proxy_port, error
+        ))
+    }
+
+    // RFC 7231 section 4.3.6
+    async fn http_connect(
+        &self,
+        tcp_stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<()> {
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+        if let Some((username, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", username, password))
+            ));
+        }
+        request.push_str("\r\n");
+
+        tcp_stream.write_all(request.as_bytes()).await?;
+
+        // Read a byte at a time so nothing after the response header is consumed.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_CONNECT_RESPONSE_LENGTH {
+                return Err(protocol_error("http connect response too long"));
+            }
+            response.push(tcp_stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+
+        match status_line.split_whitespace().nth(1) {
+            Some(status_code) if status_code.starts_with('2') => Ok(()),
+            _ => Err(std::io::Error::other(format!(
+                "http connect response {}",
+                status_line
+            ))),
+        }
+    }
+
+    async fn socks5_connect(
+        &self,
+        tcp_stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<()> {
+        let greeting = match self.credentials {
+            None => vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_METHOD_NONE],
+            Some(_) => vec![
+                SOCKS5_VERSION,
+                2,
+                SOCKS5_AUTH_METHOD_NONE,
+                SOCKS5_AUTH_METHOD_USERNAME_PASSWORD,
+            ],
+        };
+        tcp_stream.write_all(&greeting).await?;
+
+        let mut method_selection = [0u8; 2];
+        tcp_stream.read_exact(&mut method_selection).await?;
+        if method_selection[0] != SOCKS5_VERSION {
+            return Err(protocol_error("unexpected socks version"));
+        }
+
+        match (method_selection[1], &self.credentials) {
+            (SOCKS5_AUTH_METHOD_NONE, _) => {}
+            (SOCKS5_AUTH_METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+                let mut authentication_request = vec![SOCKS5_USERNAME_PASSWORD_VERSION];
+                authentication_request.push(username.len() as u8);
+                authentication_request.extend_from_slice(username.as_bytes());
+                authentication_request.push(password.len() as u8);
+                authentication_request.extend_from_slice(password.as_bytes());
+                tcp_stream.write_all(&authentication_request).await?;
+
+                let mut authentication_response = [0u8; 2];
+                tcp_stream.read_exact(&mut authentication_response).await?;
+                if authentication_response[1] != 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        "socks5 authentication failed",
+                    ));
+                }
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "socks5 no acceptable authentication method",
+                ))
+            }
+        }
+
+        let mut connect_request = vec![SOCKS5_VERSION, SOCKS5_COMMAND_CONNECT, 0];
+        match IpAddr::from_str(host) {
+            Ok(IpAddr::V4(ipv4_address)) => {
+                connect_request.push(SOCKS5_ADDRESS_TYPE_IPV4);
+                connect_request.extend_from_slice(&ipv4_address.octets());
+            }
+            Ok(IpAddr::V6(ipv6_address)) => {
+                connect_request.push(SOCKS5_ADDRESS_TYPE_IPV6);
+                connect_request.extend_from_slice(&ipv6_address.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "socks5 host name too long",
+                    ));
+                }
+                connect_request.push(SOCKS5_ADDRESS_TYPE_DOMAIN_NAME);
+                connect_request.push(host.len() as u8);
+                connect_request.extend_from_slice(host.as_bytes());
+            }
+        }
+        connect_request.extend_from_slice(&port.to_be_bytes());
+        tcp_stream.write_all(&connect_request).await?;
+
+        let mut reply_header = [0u8; 4];
+        tcp_stream.read_exact(&mut reply_header).await?;
+        if reply_header[0] != SOCKS5_VERSION {
+            return Err(protocol_error("unexpected socks version"));
+        }
+        if reply_header[1] != SOCKS5_REPLY_SUCCEEDED {
+            return Err(std::io::Error::other(format!(
+                "socks5 connect reply {}: {}",
+                reply_header[1],
+                socks5_reply_message(reply_header[1])
+            )));
+        }
+
+        // Skip the bound address and port.
+        let bound_address_length = match reply_header[3] {
+            SOCKS5_ADDRESS_TYPE_IPV4 => 4,
+            SOCKS5_ADDRESS_TYPE_IPV6 => 16,
+            SOCKS5_ADDRESS_TYPE_DOMAIN_NAME => tcp_stream.read_u8().await? as usize,
+            _ => return Err(protocol_error("unexpected socks5 bound address type")),
+        };
+        let mut bound_address = vec![0u8; bound_address_length + 2];
+        tcp_stream.read_exact(&mut bound_address).await?;
+
+        Ok(())
+    }
+}
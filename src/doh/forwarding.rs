@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+
+use crate::doh::client::DOHClient;
+use crate::doh::config::ForwardingRuleConfiguration;
+use crate::doh::request_key::RequestKey;
+
+fn normalize_domain_suffix(domain_suffix: &str) -> String {
+    let mut domain_suffix = domain_suffix.to_ascii_lowercase();
+    if !domain_suffix.ends_with('.') {
+        domain_suffix.push('.');
+    }
+    domain_suffix
+}
+
+pub struct ForwardingRule {
+    domain_suffix: String,
+    upstream_name: String,
+    matches: AtomicU64,
+}
+
+impl ForwardingRule {
+    pub fn domain_suffix(&self) -> &String {
+        &self.domain_suffix
+    }
+
+    pub fn upstream_name(&self) -> &String {
+        &self.upstream_name
+    }
+
+    pub fn increment_matches(&self) {
+        self.matches.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct ForwardingRules {
+    rules: Vec<ForwardingRule>,
+    domain_suffix_to_rule_index: HashMap<String, usize>,
+}
+
+impl ForwardingRules {
+    pub fn new(
+        forwarding_rule_configurations: Vec<ForwardingRuleConfiguration>,
+        doh_client: &DOHClient,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut rules = Vec::with_capacity(forwarding_rule_configurations.len());
+        let mut domain_suffix_to_rule_index = HashMap::new();
+
+        for forwarding_rule_configuration in forwarding_rule_configurations {
+            let domain_suffix =
+                normalize_domain_suffix(forwarding_rule_configuration.domain_suffix());
+            let upstream_name = forwarding_rule_configuration.upstream_name().clone();
+
+            if !doh_client.contains_upstream(&upstream_name) {
+                return Err(format!(
+                    "forwarding rule {} names unknown upstream {}",
+                    domain_suffix, upstream_name
+                )
+                .into());
+            }
+
+            if domain_suffix_to_rule_index
+                .insert(domain_suffix.clone(), rules.len())
+                .is_some()
+            {
+                return Err(format!("duplicate forwarding rule {}", domain_suffix).into());
+            }
+
+            rules.push(ForwardingRule {
+                domain_suffix,
+                upstream_name,
+                matches: AtomicU64::new(0),
+            });
+        }
+
+        info!("created forwarding rules len {}", rules.len());
+
+        Ok(ForwardingRules {
+            rules,
+            domain_suffix_to_rule_index,
+        })
+    }
+
+    // Returns the rule with the longest domain suffix matching the request name.
+    pub fn find_rule(&self, request_key: &RequestKey) -> Option<&ForwardingRule> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let mut name = request_key.first_name();
+        loop {
+            if let Some(rule_index) = self.domain_suffix_to_rule_index.get(name) {
+                return Some(&self.rules[*rule_index]);
+            }
+
+            name = match name.find('.') {
+                Some(dot_index) if (dot_index + 1) < name.len() => &name[(dot_index + 1)..],
+                Some(_) if name != "." => ".",
+                _ => return None,
+            };
+        }
+    }
+
+    pub fn metrics_string(&self) -> String {
+        self.rules
+            .iter()
+            .map(|rule| {
+                format!(
+                    "forwarding_rule[{}]={}",
+                    rule.domain_suffix,
+                    rule.matches.load(Ordering::Relaxed)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    use trust_dns_proto::op::{Message, Query};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use crate::doh::metrics::Metrics;
+    use crate::doh::testutil;
+
+    fn build_doh_client() -> DOHClient {
+        DOHClient::new(
+            testutil::build_client_configuration_with_upstreams(
+                ["a", "b", "c"]
+                    .iter()
+                    .map(|name| {
+                        testutil::upstream_configuration_json(
+                            name,
+                            "https://dns.example/dns-query",
+                            serde_json::json!({}),
+                        )
+                    })
+                    .collect(),
+                serde_json::json!({}),
+            ),
+            Metrics::new(),
+        )
+        .unwrap()
+    }
+
+    fn build_forwarding_rules(
+        doh_client: &DOHClient,
+        rules: &[(&str, &str)],
+    ) -> Result<ForwardingRules, Box<dyn Error>> {
+        ForwardingRules::new(
+            rules
+                .iter()
+                .map(|(domain_suffix, upstream_name)| {
+                    serde_json::from_value(serde_json::json!({
+                        "domain_suffix": domain_suffix,
+                        "upstream_name": upstream_name,
+                    }))
+                    .unwrap()
+                })
+                .collect(),
+            doh_client,
+        )
+    }
+
+    fn build_request_key(names: &[&str]) -> RequestKey {
+        let mut message = Message::new();
+        for name in names {
+            message.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        }
+        RequestKey::try_from(&message).unwrap()
+    }
+
+    fn find_upstream_name<'a>(
+        forwarding_rules: &'a ForwardingRules,
+        names: &[&str],
+    ) -> Option<&'a str> {
+        forwarding_rules
+            .find_rule(&build_request_key(names))
+            .map(|rule| rule.upstream_name().as_str())
+    }
+
+    #[test]
+    fn find_rule() {
+        let doh_client = build_doh_client();
+        let forwarding_rules = build_forwarding_rules(
+            &doh_client,
+            &[
+                ("example.com", "a"),
+                ("sub.example.com.", "b"),
+                ("Example.ORG", "c"),
+            ],
+        )
+        .unwrap();
+
+        let tests: &[(&[&str], Option<&str>)] = &[
+            // Exact name, with or without a trailing dot in the rule.
+            (&["example.com."], Some("a")),
+            (&["sub.example.com."], Some("b")),
+            (&["example.org."], Some("c")),
+            // Subdomains.
+            (&["www.example.com."], Some("a")),
+            (&["a.b.example.org."], Some("c")),
+            // Longest suffix wins.
+            (&["www.sub.example.com."], Some("b")),
+            (&["othersub.example.com."], Some("a")),
+            // Case insensitive.
+            (&["WWW.Example.Com."], Some("a")),
+            (&["www.EXAMPLE.org."], Some("c")),
+            // Only whole labels match.
+            (&["notexample.com."], None),
+            (&["example.com.net."], None),
+            (&["com."], None),
+            (&["."], None),
+            // Query keys are sorted, so the rule comes from the name that sorts first rather
+            // than the first query.
+            (&["www.example.org.", "www.example.com."], Some("a")),
+        ];
+
+        for (names, expected_upstream_name) in tests {
+            assert_eq!(
+                find_upstream_name(&forwarding_rules, names),
+                *expected_upstream_name,
+                "names {:?}",
+                names
+            );
+        }
+    }
+
+    #[test]
+    fn find_rule_root_domain_suffix() {
+        let doh_client = build_doh_client();
+        let forwarding_rules =
+            build_forwarding_rules(&doh_client, &[(".", "a"), ("example.com", "b")]).unwrap();
+
+        assert_eq!(find_upstream_name(&forwarding_rules, &["."]), Some("a"));
+        assert_eq!(
+            find_upstream_name(&forwarding_rules, &["example.net."]),
+            Some("a")
+        );
+        assert_eq!(
+            find_upstream_name(&forwarding_rules, &["www.example.com."]),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn new_rejects_invalid_rules() {
+        let doh_client = build_doh_client();
+
+        assert!(build_forwarding_rules(&doh_client, &[("example.com", "unknown")]).is_err());
+        assert!(build_forwarding_rules(
+            &doh_client,
+            &[("example.com", "a"), ("EXAMPLE.com.", "b")]
+        )
+        .is_err());
+    }
+}
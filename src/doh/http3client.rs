@@ -0,0 +1,373 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use quic_bytes::{Buf, Bytes};
+
+use crate::doh::client::{
+    doh_get_url, is_http_response_error, DOHRequestError, DOHRequestErrorType, DOH_MIME_TYPE,
+    MAX_CONTENT_LENGTH,
+};
+use crate::doh::config::{DOHRequestMethod, HTTP3Configuration, TLSConfiguration};
+use crate::doh::connectionslot::{ConnectionSlot, SlotConnection};
+use crate::doh::connector::UpstreamConnector;
+use crate::doh::httpcache::HTTPCacheHeaders;
+use crate::doh::quic::{self, QUICError};
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+struct HTTP3Connection {
+    _endpoint: quinn::Endpoint,
+    send_request: SendRequest,
+    closed: Arc<AtomicBool>,
+}
+
+impl SlotConnection for HTTP3Connection {
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl HTTP3Connection {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+// RFC 9114 HTTP/3 client for a DoH upstream.  After a failure DOHClient uses HTTP/2 for
+// fallback_retry_seconds before trying HTTP/3 again.
+pub struct HTTP3Client {
+    url: String,
+    request_method: DOHRequestMethod,
+    server_name: String,
+    server_port: u16,
+    upstream_connector: UpstreamConnector,
+    client_config: quinn::ClientConfig,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    fallback_retry_duration: Duration,
+    connection_slot: ConnectionSlot<HTTP3Connection>,
+    fallback_until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl HTTP3Client {
+    pub fn new(
+        url: &str,
+        request_method: DOHRequestMethod,
+        tls_configuration: Option<&TLSConfiguration>,
+        http3_configuration: &HTTP3Configuration,
+        upstream_connector: UpstreamConnector,
+    ) -> Result<Self, Box<dyn Error>> {
+        let parsed_url = url::Url::parse(url)?;
+
+        let server_name = parsed_url
+            .host_str()
+            .ok_or_else(|| format!("http3 url has no host: {}", url))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let server_port = parsed_url.port().unwrap_or(443);
+
+        Ok(HTTP3Client {
+            url: url.to_string(),
+            request_method,
+            server_name,
+            server_port,
+            upstream_connector,
+            client_config: quic::build_quic_client_config(tls_configuration, vec![b"h3".to_vec()])?,
+            connect_timeout: Duration::from_millis(
+                http3_configuration.connect_timeout_milliseconds(),
+            ),
+            request_timeout: Duration::from_millis(
+                http3_configuration.request_timeout_milliseconds(),
+            ),
+            fallback_retry_duration: Duration::from_secs(
+                http3_configuration.fallback_retry_seconds(),
+            ),
+            connection_slot: ConnectionSlot::new("http3"),
+            fallback_until: std::sync::Mutex::new(None),
+        })
+    }
+
+    // False while falling back to HTTP/2 after an HTTP/3 failure.
+    pub fn available(&self) -> bool {
+        let mut fallback_until = self.fallback_until.lock().unwrap();
+
+        match *fallback_until {
+            Some(instant) if Instant::now() < instant => false,
+            Some(_) => {
+                info!("retrying http3 for {}", self.url);
+                *fallback_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn start_fallback(&self) {
+        *self.fallback_until.lock().unwrap() = Some(Instant::now() + self.fallback_retry_duration);
+    }
+
+    async fn connect(&self) -> Result<Arc<HTTP3Connection>, QUICError> {
+        let (endpoint, quinn_connection) = quic::connect(
+            &self.upstream_connector,
+            &self.client_config,
+            &self.server_name,
+            self.server_port,
+            self.connect_timeout,
+        )
+        .await?;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let driver_closed = Arc::clone(&closed);
+        let url = self.url.clone();
+
+        let send_request = quic::run(async move {
+            let (mut driver, send_request) =
+                h3::client::new(h3_quinn::Connection::new(quinn_connection)).await?;
+
+            quic::spawn(async move {
+                let e = driver.wait_idle().await;
+                debug!("http3 connection to {} closed {}", url, e);
+                driver_closed.store(true, Ordering::Relaxed);
+            });
+
+            Ok(send_request)
+        })
+        .await?;
+
+        info!(
+            "connected to http3 server {}:{}",
+            self.server_name, self.server_port
+        );
+
+        Ok(Arc::new(HTTP3Connection {
+            _endpoint: endpoint,
+            send_request,
+            closed,
+        }))
+    }
+
+    fn build_request(
+        &self,
+        request_buffer: &[u8],
+    ) -> Result<(http::Request<()>, Option<Bytes>), Box<dyn Error>> {
+        Ok(match self.request_method {
+            DOHRequestMethod::Get => (
+                http::Request::get(doh_get_url(&self.url, request_buffer)?.as_str())
+                    .header(http::header::ACCEPT, DOH_MIME_TYPE)
+                    .body(())?,
+                None,
+            ),
+            DOHRequestMethod::Post => (
+                http::Request::post(&self.url)
+                    .header(http::header::CONTENT_TYPE, DOH_MIME_TYPE)
+                    .header(http::header::ACCEPT, DOH_MIME_TYPE)
+                    .body(())?,
+                Some(Bytes::copy_from_slice(request_buffer)),
+            ),
+        })
+    }
+
+    async fn send_request(
+        &self,
+        connection: &HTTP3Connection,
+        request: http::Request<()>,
+        request_body: Option<Bytes>,
+    ) -> Result<(Vec<u8>, HTTPCacheHeaders), QUICError> {
+        let mut send_request = connection.send_request.clone();
+        let request_timeout = self.request_timeout;
+
+        // Timed out on the QUIC runtime so the request is cancelled, not just abandoned.
+        let request_future = async move {
+            let mut request_stream = send_request.send_request(request).await?;
+
+            if let Some(request_body) = request_body {
+                request_stream.send_data(request_body).await?;
+            }
+            request_stream.finish().await?;
+
+            let response = request_stream.recv_response().await?;
+
+            debug!(
+                "after http3 request response status = {}",
+                response.status()
+            );
+
+            if response.status() != http::StatusCode::OK {
+                warn!(
+                    "got http3 error response status {}",
+                    response.status().as_u16()
+                );
+                return Err(
+                    DOHRequestError::new(DOHRequestErrorType::HTTPRequestError) as QUICError
+                );
+            }
+
+            let header_value = |header_name| {
+                response
+                    .headers()
+                    .get(header_name)
+                    .and_then(|header_value: &http::HeaderValue| header_value.to_str().ok())
+            };
+            let http_cache_headers = HTTPCacheHeaders::from_header_values(
+                header_value(http::header::CACHE_CONTROL),
+                header_value(http::header::AGE),
+            );
+
+            let mut response_buffer = Vec::new();
+
+            while let Some(mut chunk) = request_stream.recv_data().await? {
+                if (response_buffer.len() + chunk.remaining()) as u64 > MAX_CONTENT_LENGTH {
+                    warn!(
+                        "got too long http3 response body length > {}",
+                        MAX_CONTENT_LENGTH
+                    );
+                    return Err(DOHRequestError::new(
+                        DOHRequestErrorType::ContentLengthTooLong,
+                    ));
+                }
+                while chunk.has_remaining() {
+                    let chunk_length = chunk.chunk().len();
+                    response_buffer.extend_from_slice(chunk.chunk());
+                    chunk.advance(chunk_length);
+                }
+            }
+
+            Ok((response_buffer, http_cache_headers))
+        };
+
+        quic::run(async move {
+            quic_tokio::time::timeout(request_timeout, request_future)
+                .await
+                .map_err(|_| format!("http3 request timeout after {:?}", request_timeout))?
+        })
+        .await
+    }
+
+    pub async fn make_doh_request(
+        &self,
+        request_buffer: &[u8],
+    ) -> Result<(Vec<u8>, HTTPCacheHeaders), Box<dyn Error>> {
+        let (request, request_body) = self.build_request(request_buffer)?;
+
+        let request = &request;
+        let request_body = &request_body;
+
+        self.connection_slot
+            .make_request(
+                || self.connect(),
+                |connection| async move {
+                    let result = self
+                        .send_request(&connection, request.clone(), request_body.clone())
+                        .await;
+                    // Any error other than an HTTP response error may have broken the
+                    // connection, so the next request opens a new one.
+                    if let Err(e) = &result {
+                        if !is_http_response_error(e.as_ref()) {
+                            connection.close();
+                        }
+                    }
+                    result
+                },
+            )
+            .await
+            .map_err(quic::map_quic_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::doh::testutil::{self, TestDOHServer, TestFile, TestPKI};
+    use crate::doh::utils;
+
+    fn build_http3_client(pki: &TestPKI, server: &TestDOHServer) -> (HTTP3Client, TestFile) {
+        let root_certificate_file = pki.root_certificate_file();
+        let http3_configuration: HTTP3Configuration = serde_json::from_value(serde_json::json!({
+            "connect_timeout_milliseconds": 1000,
+            "request_timeout_milliseconds": 1000,
+            "fallback_retry_seconds": 1,
+        }))
+        .unwrap();
+
+        let http3_client = HTTP3Client::new(
+            &server.url(),
+            DOHRequestMethod::Post,
+            Some(&testutil::build_tls_configuration(&root_certificate_file)),
+            &http3_configuration,
+            testutil::build_upstream_connector(&server.url()),
+        )
+        .unwrap();
+
+        (http3_client, root_certificate_file)
+    }
+
+    fn build_request_buffer() -> Vec<u8> {
+        utils::encode_dns_message(testutil::build_request_message()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn make_doh_request() {
+        let pki = TestPKI::new();
+        let server = TestDOHServer::start(&pki).await;
+        server.start_http3(&pki).await;
+        let (http3_client, _root_certificate_file) = build_http3_client(&pki, &server);
+
+        let request_buffer = build_request_buffer();
+        let (response_buffer, _) = http3_client
+            .make_doh_request(&request_buffer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response_buffer,
+            testutil::build_response_buffer(&request_buffer)
+        );
+        assert_eq!(server.http3_requests(), 1);
+        assert_eq!(server.https_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn error_response_keeps_connection() {
+        let pki = TestPKI::new();
+        let server = TestDOHServer::start(&pki).await;
+        server.start_http3(&pki).await;
+        let (http3_client, _root_certificate_file) = build_http3_client(&pki, &server);
+        let request_buffer = build_request_buffer();
+
+        server.set_response_status(500);
+        let error = http3_client
+            .make_doh_request(&request_buffer)
+            .await
+            .unwrap_err();
+        assert!(is_http_response_error(error.as_ref()));
+
+        server.set_response_status(200);
+        http3_client
+            .make_doh_request(&request_buffer)
+            .await
+            .unwrap();
+
+        assert_eq!(server.http3_requests(), 2);
+        assert_eq!(server.http3_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_error_is_not_http_response_error() {
+        let pki = TestPKI::new();
+        // Nothing listens on the UDP port.
+        let server = TestDOHServer::start(&pki).await;
+        let (http3_client, _root_certificate_file) = build_http3_client(&pki, &server);
+
+        let error = http3_client
+            .make_doh_request(&build_request_buffer())
+            .await
+            .unwrap_err();
+        assert!(!is_http_response_error(error.as_ref()));
+    }
+}
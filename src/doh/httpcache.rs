@@ -0,0 +1,147 @@
+use hyper::header::{HeaderMap, AGE, CACHE_CONTROL};
+use log::debug;
+
+fn header_string(
+    header_map: &HeaderMap,
+    header_name: &hyper::header::HeaderName,
+) -> Option<String> {
+    let header_value = header_map.get(header_name)?;
+    match header_value.to_str() {
+        Ok(header_value) => Some(header_value.to_string()),
+        Err(e) => {
+            debug!("invalid {} header value {}", header_name, e);
+            None
+        }
+    }
+}
+
+// Cache-Control and Age headers from a DoH response.  RFC 8484 section 5.1: the HTTP freshness
+// lifetime is tied to the DNS TTL, so time a response spent in an HTTP cache is subtracted from
+// its TTLs.  Empty for responses that did not come over HTTP.
+#[derive(Debug, Clone, Default)]
+pub struct HTTPCacheHeaders {
+    cache_control: Option<String>,
+    age: Option<String>,
+}
+
+impl HTTPCacheHeaders {
+    pub fn new(header_map: &HeaderMap) -> Self {
+        HTTPCacheHeaders {
+            cache_control: header_string(header_map, &CACHE_CONTROL),
+            age: header_string(header_map, &AGE),
+        }
+    }
+
+    // For responses whose headers are not a hyper HeaderMap, e.g. HTTP/3.
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    pub fn from_header_values(cache_control: Option<&str>, age: Option<&str>) -> Self {
+        HTTPCacheHeaders {
+            cache_control: cache_control.map(str::to_string),
+            age: age.map(str::to_string),
+        }
+    }
+
+    // RFC 7234 section 5.2.2.8
+    pub fn max_age_seconds(&self) -> Option<u32> {
+        self.cache_control
+            .as_ref()?
+            .split(',')
+            .filter_map(|directive| {
+                let mut parts = directive.trim().splitn(2, '=');
+                let name = parts.next()?.trim();
+                let value = parts.next()?.trim().trim_matches('"');
+                if name.eq_ignore_ascii_case("max-age") {
+                    value.parse().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+    }
+
+    // RFC 7234 section 5.1, 0 when missing or invalid.
+    pub fn age_seconds(&self) -> u32 {
+        self.age
+            .as_ref()
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    // True if the response spent at least its max-age in an HTTP cache.
+    pub fn stale(&self) -> bool {
+        let age_seconds = self.age_seconds();
+        match self.max_age_seconds() {
+            Some(max_age_seconds) => (age_seconds > 0) && (age_seconds >= max_age_seconds),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn new_reads_headers() {
+        let mut header_map = HeaderMap::new();
+        header_map.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=300"));
+        header_map.insert(AGE, HeaderValue::from_static("20"));
+
+        let http_cache_headers = HTTPCacheHeaders::new(&header_map);
+        assert_eq!(http_cache_headers.max_age_seconds(), Some(300));
+        assert_eq!(http_cache_headers.age_seconds(), 20);
+    }
+
+    #[test]
+    fn new_missing_headers() {
+        let http_cache_headers = HTTPCacheHeaders::new(&HeaderMap::new());
+        assert_eq!(http_cache_headers.max_age_seconds(), None);
+        assert_eq!(http_cache_headers.age_seconds(), 0);
+        assert!(!http_cache_headers.stale());
+    }
+
+    #[test]
+    fn max_age_seconds() {
+        let max_age_seconds = |cache_control| {
+            HTTPCacheHeaders::from_header_values(Some(cache_control), None).max_age_seconds()
+        };
+
+        assert_eq!(max_age_seconds("max-age=60"), Some(60));
+        assert_eq!(max_age_seconds("public, max-age=60"), Some(60));
+        assert_eq!(max_age_seconds("Max-Age = 60 , no-transform"), Some(60));
+        assert_eq!(max_age_seconds(r#"max-age="60""#), Some(60));
+        assert_eq!(max_age_seconds("s-maxage=10, max-age=60"), Some(60));
+        assert_eq!(max_age_seconds("no-cache"), None);
+        assert_eq!(max_age_seconds("max-age"), None);
+        assert_eq!(max_age_seconds("max-age=-1"), None);
+        assert_eq!(max_age_seconds("max-age=abc"), None);
+        assert_eq!(max_age_seconds(""), None);
+    }
+
+    #[test]
+    fn age_seconds() {
+        let age_seconds = |age| HTTPCacheHeaders::from_header_values(None, Some(age)).age_seconds();
+
+        assert_eq!(age_seconds("30"), 30);
+        assert_eq!(age_seconds(" 30 "), 30);
+        assert_eq!(age_seconds("-1"), 0);
+        assert_eq!(age_seconds("abc"), 0);
+        assert_eq!(age_seconds(""), 0);
+    }
+
+    #[test]
+    fn stale() {
+        let stale =
+            |cache_control, age| HTTPCacheHeaders::from_header_values(cache_control, age).stale();
+
+        assert!(stale(Some("max-age=60"), Some("60")));
+        assert!(stale(Some("max-age=60"), Some("100")));
+        assert!(!stale(Some("max-age=60"), Some("59")));
+        assert!(!stale(Some("max-age=60"), None));
+        // Not from an HTTP cache.
+        assert!(!stale(Some("max-age=0"), None));
+        assert!(!stale(None, Some("100")));
+    }
+}
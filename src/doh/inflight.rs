@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::oneshot;
+use trust_dns_proto::op::Message;
+
+use crate::doh::request_key::RequestKey;
+
+type Waiters = Vec<oneshot::Sender<Option<Message>>>;
+
+pub enum InFlightRequest<'a> {
+    // No request for the key was in flight, the caller must make it and call complete.
+    Leader(InFlightRequestGuard<'a>),
+    // A request for the key is already in flight, wait for its response.
+    Waiter(oneshot::Receiver<Option<Message>>),
+}
+
+// Removes the in flight entry when the leader completes or is dropped.  Dropping without
+// completing drops the waiter senders so waiters see an error instead of hanging.
+pub struct InFlightRequestGuard<'a> {
+    in_flight_requests: &'a InFlightRequests,
+    request_key: Option<RequestKey>,
+}
+
+impl InFlightRequestGuard<'_> {
+    pub fn complete(mut self, response_message: Option<&Message>) {
+        let waiters = match self.take_waiters() {
+            None => return,
+            Some(waiters) => waiters,
+        };
+
+        for waiter in waiters {
+            let _ = waiter.send(response_message.cloned());
+        }
+    }
+
+    fn take_waiters(&mut self) -> Option<Waiters> {
+        let request_key = self.request_key.take()?;
+        self.in_flight_requests
+            .pending_requests
+            .lock()
+            .unwrap()
+            .remove(&request_key)
+    }
+}
+
+impl Drop for InFlightRequestGuard<'_> {
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+// Tracks upstream requests in flight by RequestKey so identical requests share one response.
+pub struct InFlightRequests {
+    pending_requests: Mutex<HashMap<RequestKey, Waiters>>,
+}
+
+impl InFlightRequests {
+    pub fn new() -> Self {
+        InFlightRequests {
+            pending_requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn begin(&self, request_key: &RequestKey) -> InFlightRequest<'_> {
+        let mut pending_requests = self.pending_requests.lock().unwrap();
+
+        match pending_requests.get_mut(request_key) {
+            Some(waiters) => {
+                let (sender, receiver) = oneshot::channel();
+                waiters.push(sender);
+                InFlightRequest::Waiter(receiver)
+            }
+            None => {
+                pending_requests.insert(request_key.clone(), Vec::new());
+                InFlightRequest::Leader(InFlightRequestGuard {
+                    in_flight_requests: self,
+                    request_key: Some(request_key.clone()),
+                })
+            }
+        }
+    }
+}
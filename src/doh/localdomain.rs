@@ -42,17 +42,14 @@ impl LocalDomainCache {
     }
 
     pub fn get_response_message(&self, request_key: &RequestKey) -> Option<Message> {
-        match self.cache.get(request_key) {
-            None => None,
-            Some(message) => Some(message.clone()),
-        }
+        self.cache.get(request_key).cloned()
     }
 }
 
 fn forward_domain_configuration_to_message(
     forward_domain_configuration: ForwardDomainConfiguration,
 ) -> Result<Message, Box<dyn Error>> {
-    let name = Name::from_str(&forward_domain_configuration.name())
+    let name = Name::from_str(forward_domain_configuration.name())
         .map_err(|e| format!("invalid forward name: {}", e))?;
 
     let ip_address = forward_domain_configuration.ip_address().parse()?;
@@ -78,10 +75,10 @@ fn forward_domain_configuration_to_message(
 fn reverse_domain_configuration_to_message(
     reverse_domain_configuration: ReverseDomainConfiguration,
 ) -> Result<Message, Box<dyn Error>> {
-    let reverse_address = Name::from_str(&reverse_domain_configuration.reverse_address())
+    let reverse_address = Name::from_str(reverse_domain_configuration.reverse_address())
         .map_err(|e| format!("invalid reverse_address: {}", e))?;
 
-    let name = Name::from_str(&reverse_domain_configuration.name())
+    let name = Name::from_str(reverse_domain_configuration.name())
         .map_err(|e| format!("invalid reverse name: {}", e))?;
 
     let mut message = Message::new();
@@ -14,8 +14,21 @@ pub enum CounterMetricType {
     UDPRequests,
     LocalRequests,
     CacheHits,
+    NegativeCacheHits,
     CacheMisses,
+    CoalescedRequests,
     DOHRequestErrors,
+    UpstreamMarkedDown,
+    UpstreamMarkedUp,
+    UpstreamHealthChecks,
+    InvalidUpstreamResponses,
+    UpstreamHTTP1Responses,
+    UpstreamHTTP2Responses,
+    UpstreamHTTP3Responses,
+    UpstreamHTTP3Fallbacks,
+    StaleResponses,
+    Prefetches,
+    PrefetchFailures,
 }
 
 impl CounterMetricType {
@@ -25,8 +38,21 @@ impl CounterMetricType {
             CounterMetricType::UDPRequests => "udp_requests",
             CounterMetricType::LocalRequests => "local_requests",
             CounterMetricType::CacheHits => "cache_hits",
+            CounterMetricType::NegativeCacheHits => "negative_cache_hits",
             CounterMetricType::CacheMisses => "cache_misses",
+            CounterMetricType::CoalescedRequests => "coalesced_requests",
             CounterMetricType::DOHRequestErrors => "doh_request_errors",
+            CounterMetricType::UpstreamMarkedDown => "upstream_marked_down",
+            CounterMetricType::UpstreamMarkedUp => "upstream_marked_up",
+            CounterMetricType::UpstreamHealthChecks => "upstream_health_checks",
+            CounterMetricType::InvalidUpstreamResponses => "invalid_upstream_responses",
+            CounterMetricType::UpstreamHTTP1Responses => "upstream_http1_responses",
+            CounterMetricType::UpstreamHTTP2Responses => "upstream_http2_responses",
+            CounterMetricType::UpstreamHTTP3Responses => "upstream_http3_responses",
+            CounterMetricType::UpstreamHTTP3Fallbacks => "upstream_http3_fallbacks",
+            CounterMetricType::StaleResponses => "stale_responses",
+            CounterMetricType::Prefetches => "prefetches",
+            CounterMetricType::PrefetchFailures => "prefetch_failures",
         }
     }
 }
@@ -0,0 +1,436 @@
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use getrandom::rand_core::UnwrapErr;
+use getrandom::SysRng;
+use hyper::{header, Body, Request, StatusCode};
+use log::{debug, info, warn};
+use odoh_rs::{ObliviousDoHConfigContents, ObliviousDoHConfigs, ObliviousDoHMessage};
+use odoh_rs::{ObliviousDoHMessagePlaintext, ODOH_HTTP_HEADER};
+
+use crate::doh::client::{
+    map_connection_error, read_response_body, DOHRequestError, DOHRequestErrorType,
+};
+use crate::doh::config::ODOHConfiguration;
+use crate::doh::upstream::HTTPClient;
+
+// RFC 9230 section 6.1
+const ODOH_CONFIGS_PATH: &str = "/.well-known/odohconfigs";
+
+struct CachedConfig {
+    config_contents: ObliviousDoHConfigContents,
+    fetch_time: Instant,
+}
+
+// Oblivious DoH client.  Queries are encrypted to the target's HPKE public key and sent
+// through the proxy, so the proxy sees the client address but not the query, and the target
+// sees the query but not the client address.
+pub struct ODOHClient {
+    http_client: HTTPClient,
+    proxy_url: String,
+    configs_url: String,
+    config_refresh_duration: Duration,
+    cached_config: Mutex<Option<CachedConfig>>,
+}
+
+impl ODOHClient {
+    pub fn new(
+        target_url: &str,
+        odoh_configuration: &ODOHConfiguration,
+        http_client: HTTPClient,
+    ) -> Result<Self, Box<dyn Error>> {
+        let target_url = url::Url::parse(target_url)?;
+
+        let mut target_host = target_url
+            .host_str()
+            .ok_or_else(|| format!("odoh target url has no host: {}", target_url))?
+            .to_string();
+        if let Some(port) = target_url.port() {
+            target_host = format!("{}:{}", target_host, port);
+        }
+
+        // RFC 9230 section 4.1: the proxy is told the target with query parameters.
+        let mut proxy_url = url::Url::parse(odoh_configuration.proxy_url())?;
+        proxy_url
+            .query_pairs_mut()
+            .append_pair("targethost", &target_host)
+            .append_pair("targetpath", target_url.path());
+
+        let configs_url = match odoh_configuration.configs_url() {
+            Some(configs_url) => url::Url::parse(configs_url)?,
+            None => target_url.join(ODOH_CONFIGS_PATH)?,
+        };
+
+        Ok(ODOHClient {
+            http_client,
+            proxy_url: proxy_url.into_string(),
+            configs_url: configs_url.into_string(),
+            config_refresh_duration: Duration::from_secs(
+                odoh_configuration.config_refresh_seconds(),
+            ),
+            cached_config: Mutex::new(None),
+        })
+    }
+
+    async fn make_http_request(
+        &self,
+        request: Request<Body>,
+        expected_content_type: Option<&str>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let response = self
+            .http_client
+            .request(request)
+            .await
+            .map_err(map_connection_error)?;
+
+        debug!(
+            "after odoh http request response status = {}",
+            response.status()
+        );
+
+        if response.status() != StatusCode::OK {
+            warn!(
+                "got odoh http error response status {}",
+                response.status().as_u16()
+            );
+            if response.status() == StatusCode::UNAUTHORIZED {
+                // The target did not recognize the key id, the config may have been rotated.
+                self.invalidate_config();
+            }
+            return Err(DOHRequestError::new(DOHRequestErrorType::HTTPRequestError));
+        }
+
+        if let Some(expected_content_type) = expected_content_type {
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            if content_type != expected_content_type {
+                warn!("got unexpected odoh content type {}", content_type);
+                return Err(DOHRequestError::new(DOHRequestErrorType::HTTPRequestError));
+            }
+        }
+
+        read_response_body(response.into_body()).await
+    }
+
+    async fn fetch_config(&self) -> Result<ObliviousDoHConfigContents, Box<dyn Error>> {
+        let request = Request::get(&self.configs_url).body(Body::empty())?;
+
+        let body = self
+            .make_http_request(request, None)
+            .await
+            .map_err(|e| format!("odoh config fetch error from {}: {}", self.configs_url, e))?;
+
+        let configs: ObliviousDoHConfigs = odoh_rs::parse(&mut body.as_slice())?;
+
+        let config = configs
+            .supported()
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("no supported odoh config from {}", self.configs_url))?;
+
+        info!("fetched odoh config from {}", self.configs_url);
+
+        Ok(config.into())
+    }
+
+    fn cached_config_contents(&self) -> Option<ObliviousDoHConfigContents> {
+        let cached_config = self.cached_config.lock().unwrap();
+        let cached_config = cached_config.as_ref()?;
+
+        if cached_config.fetch_time.elapsed() < self.config_refresh_duration {
+            Some(cached_config.config_contents.clone())
+        } else {
+            None
+        }
+    }
+
+    // The lock is not held during the fetch so a slow configs url does not block requests that
+    // already have a config.  Concurrent requests without a cached config each fetch it.
+    async fn get_config(&self) -> Result<ObliviousDoHConfigContents, Box<dyn Error>> {
+        if let Some(config_contents) = self.cached_config_contents() {
+            return Ok(config_contents);
+        }
+
+        let config_contents = self.fetch_config().await?;
+
+        *self.cached_config.lock().unwrap() = Some(CachedConfig {
+            config_contents: config_contents.clone(),
+            fetch_time: Instant::now(),
+        });
+
+        Ok(config_contents)
+    }
+
+    fn invalidate_config(&self) {
+        *self.cached_config.lock().unwrap() = None;
+    }
+
+    pub async fn make_odoh_request(
+        &self,
+        request_buffer: Vec<u8>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let config_contents = self.get_config().await?;
+
+        let query = ObliviousDoHMessagePlaintext::new(&request_buffer, 0);
+
+        let (encrypted_query, client_secret) =
+            odoh_rs::encrypt_query(&query, &config_contents, &mut UnwrapErr(SysRng))?;
+
+        let request = Request::post(&self.proxy_url)
+            .header(header::CONTENT_TYPE, ODOH_HTTP_HEADER)
+            .header(header::ACCEPT, ODOH_HTTP_HEADER)
+            .body(Body::from(odoh_rs::compose(&encrypted_query)?.to_vec()))?;
+
+        let body = self
+            .make_http_request(request, Some(ODOH_HTTP_HEADER))
+            .await?;
+
+        let encrypted_response: ObliviousDoHMessage = odoh_rs::parse(&mut body.as_slice())?;
+
+        let response = match odoh_rs::decrypt_response(&query, &encrypted_response, client_secret) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                warn!("odoh decrypt_response error {}", e);
+                None
+            }
+        };
+
+        let response = match response {
+            Some(response) => response,
+            None => {
+                self.invalidate_config();
+                return Err(DOHRequestError::new(DOHRequestErrorType::ODOHDecryptError));
+            }
+        };
+
+        Ok(response.into_msg().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Method, Response, Server};
+    use hyper_rustls::HttpsConnector;
+    use odoh_rs::{ObliviousDoHConfig, ObliviousDoHKeyPair, ResponseNonce};
+
+    use crate::doh::testutil;
+    use crate::doh::tls;
+
+    const TARGET_PATH: &str = "/dns-query";
+    const PROXY_PATH: &str = "/proxy";
+    const RESPONSE_PREFIX: &[u8] = b"response to ";
+
+    // Serves the target's odohconfigs and acts as the relay, answering queries itself.
+    struct TestTarget {
+        key_pair: Mutex<ObliviousDoHKeyPair>,
+        config_fetches: AtomicUsize,
+        corrupt_responses: AtomicBool,
+    }
+
+    impl TestTarget {
+        fn new() -> Arc<Self> {
+            Arc::new(TestTarget {
+                key_pair: Mutex::new(ObliviousDoHKeyPair::new(&mut UnwrapErr(SysRng))),
+                config_fetches: AtomicUsize::new(0),
+                corrupt_responses: AtomicBool::new(false),
+            })
+        }
+
+        fn rotate_key_pair(&self) {
+            *self.key_pair.lock().unwrap() = ObliviousDoHKeyPair::new(&mut UnwrapErr(SysRng));
+        }
+
+        fn configs_response(&self) -> Response<Body> {
+            self.config_fetches.fetch_add(1, Ordering::SeqCst);
+
+            let config_contents = self.key_pair.lock().unwrap().public().clone();
+            let configs: ObliviousDoHConfigs =
+                vec![ObliviousDoHConfig::from(config_contents)].into();
+
+            Response::new(Body::from(odoh_rs::compose(&configs).unwrap().to_vec()))
+        }
+
+        fn query_response(&self, request_query: Option<&str>, body: &[u8]) -> Response<Body> {
+            // RFC 9230 section 4.1: the relay finds the target from the query parameters.
+            let target_parameters: Vec<(String, String)> =
+                url::form_urlencoded::parse(request_query.unwrap_or_default().as_bytes())
+                    .into_owned()
+                    .collect();
+            match target_parameters.as_slice() {
+                [(host_key, host), (path_key, path)]
+                    if host_key == "targethost"
+                        && host.starts_with("127.0.0.1:")
+                        && path_key == "targetpath"
+                        && path == TARGET_PATH => {}
+                _ => return status_response(StatusCode::BAD_REQUEST),
+            }
+
+            let encrypted_query: ObliviousDoHMessage = match odoh_rs::parse(&mut &body[..]) {
+                Ok(encrypted_query) => encrypted_query,
+                Err(_) => return status_response(StatusCode::BAD_REQUEST),
+            };
+
+            // RFC 9230 section 4.3: an unknown key id gets a 401 response.
+            let decrypt_result =
+                odoh_rs::decrypt_query(&encrypted_query, &self.key_pair.lock().unwrap());
+            let (query, server_secret) = match decrypt_result {
+                Ok(decrypt_result) => decrypt_result,
+                Err(_) => return status_response(StatusCode::UNAUTHORIZED),
+            };
+
+            let mut response_message = RESPONSE_PREFIX.to_vec();
+            response_message.extend_from_slice(&query.clone().into_msg());
+            let response = ObliviousDoHMessagePlaintext::new(&response_message, 0);
+
+            let encrypted_response = odoh_rs::encrypt_response(
+                &query,
+                &response,
+                server_secret,
+                ResponseNonce::default(),
+            )
+            .unwrap();
+            let mut response_body = odoh_rs::compose(&encrypted_response).unwrap().to_vec();
+
+            if self.corrupt_responses.load(Ordering::SeqCst) {
+                let last_byte = response_body.last_mut().unwrap();
+                *last_byte ^= 0xff;
+            }
+
+            let mut response = Response::new(Body::from(response_body));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static(ODOH_HTTP_HEADER),
+            );
+            response
+        }
+
+        async fn handle(
+            self: Arc<Self>,
+            request: Request<Body>,
+        ) -> Result<Response<Body>, Infallible> {
+            let method = request.method().clone();
+            let path = request.uri().path().to_string();
+            let query = request.uri().query().map(str::to_string);
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+
+            Ok(match (method, path.as_str()) {
+                (Method::GET, ODOH_CONFIGS_PATH) => self.configs_response(),
+                (Method::POST, PROXY_PATH) => self.query_response(query.as_deref(), &body),
+                _ => status_response(StatusCode::NOT_FOUND),
+            })
+        }
+
+        fn start(self: &Arc<Self>) -> SocketAddr {
+            let test_target = Arc::clone(self);
+            let make_service = make_service_fn(move |_| {
+                let test_target = Arc::clone(&test_target);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        Arc::clone(&test_target).handle(request)
+                    }))
+                }
+            });
+
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+            let local_addr = server.local_addr();
+            tokio::spawn(server);
+            local_addr
+        }
+    }
+
+    fn status_response(status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    }
+
+    fn build_odoh_client(local_addr: SocketAddr) -> ODOHClient {
+        let target_url = format!("http://{}{}", local_addr, TARGET_PATH);
+        let http_client = hyper::Client::builder().build(HttpsConnector::from((
+            testutil::build_upstream_connector(&target_url),
+            tls::build_tls_config(None, Vec::new()).unwrap(),
+        )));
+
+        let odoh_configuration: ODOHConfiguration = serde_json::from_value(serde_json::json!({
+            "proxy_url": format!("http://{}{}", local_addr, PROXY_PATH),
+            "config_refresh_seconds": 3600,
+        }))
+        .unwrap();
+
+        ODOHClient::new(&target_url, &odoh_configuration, http_client).unwrap()
+    }
+
+    async fn assert_round_trip(odoh_client: &ODOHClient, request_buffer: &[u8]) {
+        let response_buffer = odoh_client
+            .make_odoh_request(request_buffer.to_vec())
+            .await
+            .unwrap();
+
+        let mut expected_response = RESPONSE_PREFIX.to_vec();
+        expected_response.extend_from_slice(request_buffer);
+        assert_eq!(response_buffer, expected_response);
+    }
+
+    #[tokio::test]
+    async fn fetch_config_and_round_trip() {
+        let test_target = TestTarget::new();
+        let odoh_client = build_odoh_client(test_target.start());
+
+        assert_round_trip(&odoh_client, b"first query").await;
+        assert_round_trip(&odoh_client, b"second query").await;
+
+        // The config is cached between requests.
+        assert_eq!(test_target.config_fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refresh_config_after_decrypt_failure() {
+        let test_target = TestTarget::new();
+        let odoh_client = build_odoh_client(test_target.start());
+
+        assert_round_trip(&odoh_client, b"first query").await;
+
+        test_target.corrupt_responses.store(true, Ordering::SeqCst);
+        let error = odoh_client
+            .make_odoh_request(b"corrupted query".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            DOHRequestError::new(DOHRequestErrorType::ODOHDecryptError).to_string()
+        );
+        test_target.corrupt_responses.store(false, Ordering::SeqCst);
+
+        assert_round_trip(&odoh_client, b"second query").await;
+        assert_eq!(test_target.config_fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_config_after_key_rotation() {
+        let test_target = TestTarget::new();
+        let odoh_client = build_odoh_client(test_target.start());
+
+        assert_round_trip(&odoh_client, b"first query").await;
+
+        test_target.rotate_key_pair();
+        assert!(odoh_client
+            .make_odoh_request(b"stale key query".to_vec())
+            .await
+            .is_err());
+
+        assert_round_trip(&odoh_client, b"second query").await;
+        assert_eq!(test_target.config_fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,193 @@
+use trust_dns_proto::error::ProtoResult;
+use trust_dns_proto::op::{Edns, Message};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::doh::utils;
+
+// RFC 8467 section 4.1: clients should pad queries to the closest multiple of 128 octets.
+const QUERY_PADDING_BLOCK_SIZE: usize = 128;
+
+const PADDING_OPTION_CODE: u16 = 12; // RFC 7830 section 4
+
+fn padding_option(padding_length: usize) -> EdnsOption {
+    EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; padding_length])
+}
+
+// Adds an EDNS(0) padding option to the query so the encoded length is a multiple of
+// QUERY_PADDING_BLOCK_SIZE, adding an OPT record if the query does not have one.
+pub fn pad_request_buffer(request_buffer: Vec<u8>) -> ProtoResult<Vec<u8>> {
+    let mut request_message = utils::decode_dns_message(request_buffer)?;
+
+    // Encode once with an empty padding option to find the unpadded length including the
+    // OPT record and padding option header.
+    request_message.edns_mut().set_option(padding_option(0));
+    let unpadded_length = utils::encode_dns_message(request_message.clone())?.len();
+
+    let padding_length = (QUERY_PADDING_BLOCK_SIZE - (unpadded_length % QUERY_PADDING_BLOCK_SIZE))
+        % QUERY_PADDING_BLOCK_SIZE;
+
+    request_message
+        .edns_mut()
+        .set_option(padding_option(padding_length));
+
+    utils::encode_dns_message(request_message)
+}
+
+// Removes any EDNS(0) padding option from the response, keeping other options.
+pub fn strip_response_padding(response_message: &mut Message) {
+    let edns = match response_message.edns() {
+        Some(edns) if edns.option(EdnsCode::Padding).is_some() => edns,
+        _ => return,
+    };
+
+    let mut stripped_edns = Edns::new();
+    stripped_edns.set_rcode_high(edns.rcode_high());
+    stripped_edns.set_version(edns.version());
+    stripped_edns.set_dnssec_ok(edns.dnssec_ok());
+    stripped_edns.set_max_payload(edns.max_payload());
+
+    for (edns_code, edns_option) in edns.options().options() {
+        if *edns_code != EdnsCode::Padding {
+            stripped_edns.set_option(edns_option.clone());
+        }
+    }
+
+    response_message.set_edns(stripped_edns);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use trust_dns_proto::op::{MessageType, Query};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    const COOKIE_OPTION_CODE: u16 = 10; // RFC 7873 section 4
+
+    fn build_request_message(name: &str) -> Message {
+        let mut request_message = Message::new();
+        request_message
+            .set_message_type(MessageType::Query)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        request_message
+    }
+
+    fn padding_length(message: &Message) -> Option<usize> {
+        let padding_option = message.edns()?.option(EdnsCode::Padding)?;
+        Some(Vec::<u8>::from(padding_option).len())
+    }
+
+    fn cookie_option() -> EdnsOption {
+        EdnsOption::Unknown(COOKIE_OPTION_CODE, vec![1; 8])
+    }
+
+    #[test]
+    fn pad_request_buffer_to_block_size() {
+        for name in &[
+            "example.com.",
+            "a.much.longer.name.in.a.subdomain.of.example.com.",
+        ] {
+            let request_buffer = utils::encode_dns_message(build_request_message(name)).unwrap();
+            assert!(request_buffer.len() < QUERY_PADDING_BLOCK_SIZE);
+
+            let padded_buffer = pad_request_buffer(request_buffer).unwrap();
+            assert_eq!(padded_buffer.len(), QUERY_PADDING_BLOCK_SIZE);
+
+            let padded_message = utils::decode_dns_message(padded_buffer).unwrap();
+            assert!(padding_length(&padded_message).unwrap() > 0);
+            assert_eq!(
+                padded_message.queries(),
+                build_request_message(name).queries()
+            );
+        }
+
+        // Over one block pads to the next.
+        let long_name = format!("{}.{}.example.com.", "a".repeat(63), "b".repeat(63));
+        let padded_buffer = pad_request_buffer(
+            utils::encode_dns_message(build_request_message(&long_name)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(padded_buffer.len(), 2 * QUERY_PADDING_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn pad_request_buffer_keeps_existing_opt_record() {
+        let mut request_message = build_request_message("example.com.");
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        edns.set_dnssec_ok(true);
+        edns.set_option(cookie_option());
+        request_message.set_edns(edns);
+
+        let padded_buffer =
+            pad_request_buffer(utils::encode_dns_message(request_message).unwrap()).unwrap();
+        assert_eq!(padded_buffer.len(), QUERY_PADDING_BLOCK_SIZE);
+
+        let padded_message = utils::decode_dns_message(padded_buffer).unwrap();
+        assert_eq!(padded_message.additionals().len(), 0);
+        let edns = padded_message.edns().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.option(EdnsCode::Cookie), Some(&cookie_option()));
+        assert!(padding_length(&padded_message).is_some());
+    }
+
+    #[test]
+    fn pad_request_buffer_at_block_boundary() {
+        // 12 byte header, 97 byte name, 4 byte question type and class, 11 byte OPT record,
+        // and 4 byte padding option header add up to one block.
+        let name = format!("{}.{}.", "a".repeat(63), "b".repeat(31));
+        let padded_buffer =
+            pad_request_buffer(utils::encode_dns_message(build_request_message(&name)).unwrap())
+                .unwrap();
+        assert_eq!(padded_buffer.len(), QUERY_PADDING_BLOCK_SIZE);
+        // trust-dns drops a zero length option when decoding, so check the encoded option.
+        assert!(padded_buffer.ends_with(&[0, PADDING_OPTION_CODE as u8, 0, 0]));
+
+        // Padding an already padded query does not grow it.
+        assert_eq!(
+            pad_request_buffer(padded_buffer.clone()).unwrap(),
+            padded_buffer
+        );
+    }
+
+    #[test]
+    fn strip_response_padding_keeps_other_options() {
+        // RFC 8467 section 4.1: servers pad responses to a multiple of 468 octets.
+        const RESPONSE_PADDING_BLOCK_SIZE: usize = 468;
+
+        let mut response_message = build_request_message("example.com.");
+        response_message.set_message_type(MessageType::Response);
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        edns.set_dnssec_ok(true);
+        edns.set_option(cookie_option());
+        edns.set_option(padding_option(0));
+        response_message.set_edns(edns);
+        let unpadded_length = utils::encode_dns_message(response_message.clone())
+            .unwrap()
+            .len();
+        response_message.edns_mut().set_option(padding_option(
+            RESPONSE_PADDING_BLOCK_SIZE - unpadded_length,
+        ));
+        let response_buffer = utils::encode_dns_message(response_message).unwrap();
+        assert_eq!(response_buffer.len(), RESPONSE_PADDING_BLOCK_SIZE);
+
+        let mut response_message = utils::decode_dns_message(response_buffer).unwrap();
+        strip_response_padding(&mut response_message);
+
+        assert_eq!(padding_length(&response_message), None);
+        let edns = response_message.edns().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.option(EdnsCode::Cookie), Some(&cookie_option()));
+        assert!(utils::encode_dns_message(response_message).unwrap().len() < 100);
+    }
+
+    #[test]
+    fn strip_response_padding_without_padding() {
+        let mut response_message = build_request_message("example.com.");
+        strip_response_padding(&mut response_message);
+        assert!(response_message.edns().is_none());
+    }
+}
@@ -4,23 +4,129 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use log::{debug, info, warn};
-use trust_dns_proto::op::Message;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use trust_dns_proto::op::{Message, ResponseCode};
 use trust_dns_proto::rr::resource::Record;
+use trust_dns_proto::rr::RData;
 
 use crate::doh::cache::{Cache, CacheObject};
-use crate::doh::client::DOHClient;
-use crate::doh::config::Configuration;
+use crate::doh::cachesnapshot;
+use crate::doh::client::{DOHClient, DOHResponse};
+use crate::doh::config::{Configuration, ProxyConfiguration};
+use crate::doh::forwarding::{ForwardingRule, ForwardingRules};
+use crate::doh::httpcache::HTTPCacheHeaders;
+use crate::doh::inflight::{InFlightRequest, InFlightRequests};
 use crate::doh::localdomain::LocalDomainCache;
 use crate::doh::metrics::{CounterMetricType, Metrics};
 use crate::doh::request_key::RequestKey;
 use crate::doh::utils;
 
+// TTLs are reduced by the time the response spent in an HTTP cache, then clamped.  A response
+// the HTTP cache says is stale, or whose TTLs the Age used up, gets a 0 duration and is not
+// cached.
+// Negative responses use the negative clamps, and their SOA record TTL is capped at the SOA
+// MINIMUM field (RFC 2308 section 5).
+fn clamp_and_get_min_ttl_duration(
+    proxy_configuration: &ProxyConfiguration,
+    response_message: Message,
+    http_cache_headers: &HTTPCacheHeaders,
+) -> (Duration, Message) {
+    let negative_response = utils::is_negative_response(&response_message);
+
+    let (clamp_min_ttl_seconds, clamp_max_ttl_seconds) = if negative_response {
+        (
+            proxy_configuration.negative_clamp_min_ttl_seconds(),
+            proxy_configuration.negative_clamp_max_ttl_seconds(),
+        )
+    } else {
+        (
+            proxy_configuration.clamp_min_ttl_seconds(),
+            proxy_configuration.clamp_max_ttl_seconds(),
+        )
+    };
+
+    let age_seconds = http_cache_headers.age_seconds();
+
+    let mut stale = http_cache_headers.stale();
+
+    let mut found_record_ttl = false;
+    let mut record_min_ttl_seconds = clamp_min_ttl_seconds;
+
+    let mut found_soa_record = false;
+
+    let mut process_record = |record: Record| -> Record {
+        let ttl = match record.rdata() {
+            RData::SOA(soa) if negative_response => {
+                found_soa_record = true;
+                std::cmp::min(record.ttl(), soa.minimum())
+            }
+            _ => record.ttl(),
+        };
+
+        let ttl = if age_seconds > 0 {
+            let ttl = ttl.saturating_sub(age_seconds);
+            if ttl == 0 {
+                stale = true;
+            }
+            ttl
+        } else {
+            ttl
+        };
+
+        let ttl = std::cmp::max(ttl, clamp_min_ttl_seconds);
+        let ttl = std::cmp::min(ttl, clamp_max_ttl_seconds);
+
+        if (!found_record_ttl) || (ttl < record_min_ttl_seconds) {
+            record_min_ttl_seconds = ttl;
+            found_record_ttl = true;
+        }
+
+        let mut record = record;
+        record.set_ttl(ttl);
+
+        record
+    };
+
+    let mut response_message = response_message;
+
+    for record in response_message.take_answers() {
+        response_message.add_answer(process_record(record));
+    }
+    for record in response_message.take_name_servers() {
+        response_message.add_name_server(process_record(record));
+    }
+    for record in response_message.take_additionals() {
+        response_message.add_additional(process_record(record));
+    }
+
+    if stale {
+        debug!("not caching stale http response age {}", age_seconds);
+        return (Duration::from_secs(0), response_message);
+    }
+
+    // RFC 2308 section 5: negative responses without an SOA record should not be cached.
+    if negative_response && !found_soa_record {
+        debug!("not caching negative response without soa record");
+        return (Duration::from_secs(0), response_message);
+    }
+
+    (
+        Duration::from_secs(record_min_ttl_seconds.into()),
+        response_message,
+    )
+}
+
 pub struct DOHProxy {
     configuration: Configuration,
     local_domain_cache: LocalDomainCache,
+    forwarding_rules: ForwardingRules,
     cache: Cache,
-    doh_client: DOHClient,
+    in_flight_requests: InFlightRequests,
+    doh_client: Arc<DOHClient>,
     metrics: Arc<Metrics>,
+    // Held while writing the cache snapshot, so the periodic and shutdown writes do not overlap.
+    cache_snapshot_mutex: Mutex<()>,
 }
 
 impl DOHProxy {
@@ -29,6 +135,11 @@ impl DOHProxy {
         let reverse_domain_configurations = configuration.reverse_domain_configurations().clone();
         let cache_configuration = configuration.cache_configuration().clone();
         let client_configuration = configuration.client_configuration().clone();
+        let forwarding_rule_configurations = configuration.forwarding_rule_configurations().clone();
+
+        let metrics = Metrics::new();
+        let doh_client = Arc::new(DOHClient::new(client_configuration, Arc::clone(&metrics))?);
+        let forwarding_rules = ForwardingRules::new(forwarding_rule_configurations, &doh_client)?;
 
         Ok(Arc::new(DOHProxy {
             configuration,
@@ -36,9 +147,12 @@ impl DOHProxy {
                 forward_domain_configurations,
                 reverse_domain_configurations,
             )?,
+            forwarding_rules,
             cache: Cache::new(cache_configuration),
-            doh_client: DOHClient::new(client_configuration)?,
-            metrics: Metrics::new(),
+            in_flight_requests: InFlightRequests::new(),
+            doh_client,
+            metrics,
+            cache_snapshot_mutex: Mutex::new(()),
         }))
     }
 
@@ -59,100 +173,47 @@ impl DOHProxy {
         }
     }
 
-    async fn make_doh_request(&self, request_message: &Message) -> Option<Message> {
-        let mut doh_request_message = request_message.clone();
-        doh_request_message.set_id(0);
-        let request_buffer = match utils::encode_dns_message(doh_request_message) {
-            Err(e) => {
-                warn!("encode_dns_message error {}", e);
-                return None;
-            }
-            Ok(buffer) => buffer,
-        };
-
-        let response_buffer = match self.doh_client.make_doh_request(request_buffer).await {
+    async fn make_doh_request(
+        &self,
+        request_message: &Message,
+        forwarding_rule: Option<&ForwardingRule>,
+    ) -> Option<DOHResponse> {
+        let doh_response = match self
+            .doh_client
+            .make_doh_request(request_message, forwarding_rule)
+            .await
+        {
             Err(e) => {
                 warn!("make_doh_request error {}", e);
                 self.metrics.counter_metric(CounterMetricType::DOHRequestErrors).increment_value();
                 return None;
             }
-            Ok(response_buffer) => response_buffer,
+            Ok(doh_response) => doh_response,
         };
 
-        debug!("got response_buffer length = {}", response_buffer.len());
+        debug!("got doh_response {:#?}", doh_response);
 
-        let response_message = match utils::decode_dns_message(response_buffer) {
-            Err(e) => {
-                warn!("decode_dns_message error {}", e);
-                return None;
-            }
-            Ok(message) => message,
-        };
-
-        Some(response_message)
+        Some(doh_response)
     }
 
-    fn clamp_and_get_min_ttl_duration(&self, response_message: Message) -> (Duration, Message) {
-        let clamp_min_ttl_seconds = self
-            .configuration
-            .proxy_configuration()
-            .clamp_min_ttl_seconds();
-        let clamp_max_ttl_seconds = self
-            .configuration
-            .proxy_configuration()
-            .clamp_max_ttl_seconds();
-
-        let mut found_record_ttl = false;
-        let mut record_min_ttl_seconds = clamp_min_ttl_seconds;
-
-        let mut process_record = |record: Record| -> Record {
-            let ttl = record.ttl();
-
-            let ttl = std::cmp::max(ttl, clamp_min_ttl_seconds);
-            let ttl = std::cmp::min(ttl, clamp_max_ttl_seconds);
-
-            if (!found_record_ttl) || (ttl < record_min_ttl_seconds) {
-                record_min_ttl_seconds = ttl;
-                found_record_ttl = true;
-            }
-
-            let mut record = record;
-            record.set_ttl(ttl);
-
-            record
-        };
-
-        let mut response_message = response_message;
-
-        for record in response_message.take_answers() {
-            response_message.add_answer(process_record(record));
-        }
-        for record in response_message.take_name_servers() {
-            response_message.add_name_server(process_record(record));
-        }
-        for record in response_message.take_additionals() {
-            response_message.add_additional(process_record(record));
-        }
-
-        (
-            Duration::from_secs(record_min_ttl_seconds.into()),
-            response_message,
-        )
-    }
-
-    async fn clamp_ttl_and_cache_response(
+    fn clamp_ttl_and_cache_response(
         &self,
         request_key: RequestKey,
-        response_message: Message,
+        doh_response: DOHResponse,
     ) -> Message {
-        if !((response_message.response_code() == trust_dns_proto::op::ResponseCode::NoError)
-            || (response_message.response_code() == trust_dns_proto::op::ResponseCode::NXDomain))
+        let response_code = doh_response.response_message().response_code();
+        if !((response_code == trust_dns_proto::op::ResponseCode::NoError)
+            || (response_code == trust_dns_proto::op::ResponseCode::NXDomain))
         {
-            return response_message;
+            return doh_response.into_response_message();
         }
 
-        let (min_ttl_duration, response_message) =
-            self.clamp_and_get_min_ttl_duration(response_message);
+        let http_cache_headers = doh_response.http_cache_headers().clone();
+        let (min_ttl_duration, response_message) = clamp_and_get_min_ttl_duration(
+            self.configuration.proxy_configuration(),
+            doh_response.into_response_message(),
+            &http_cache_headers,
+        );
 
         if min_ttl_duration.as_secs() == 0 {
             return response_message;
@@ -162,12 +223,19 @@ impl DOHProxy {
 
         let mut response_message_clone = response_message.clone();
         response_message_clone.set_id(0);
-        self.cache
-            .put(
-                request_key,
-                CacheObject::new(response_message_clone, now, min_ttl_duration),
-            )
-            .await;
+        let negative_response = utils::is_negative_response(&response_message_clone);
+
+        let cache_object = utils::encode_dns_message(response_message_clone)
+            .map_err(|e| e.to_string())
+            .and_then(|message_buffer| {
+                CacheObject::new(message_buffer, negative_response, now, min_ttl_duration)
+                    .map_err(|e| e.to_string())
+            });
+
+        match cache_object {
+            Err(e) => warn!("not caching response: {}", e),
+            Ok(cache_object) => self.cache.put(request_key, cache_object),
+        }
 
         response_message
     }
@@ -177,86 +245,109 @@ impl DOHProxy {
         request_key: &RequestKey,
         request_id: u16,
     ) -> Option<Message> {
-        let mut response_message = match self.local_domain_cache.get_response_message(&request_key)
-        {
-            None => return None,
-            Some(message) => message,
-        };
+        let mut response_message = self.local_domain_cache.get_response_message(request_key)?;
 
         response_message.set_id(request_id);
 
         Some(response_message)
     }
 
-    async fn get_message_for_cache_hit(
+    // Returns the expired cache object if it can still be served stale, RFC 8767.
+    fn get_stale_cache_object(
         &self,
-        request_key: &RequestKey,
-        request_id: u16,
-    ) -> Option<Message> {
-        let cache_object = match self.cache.get(&request_key).await {
-            None => return None,
-            Some(cache_object) => cache_object,
-        };
+        cache_object: CacheObject,
+        now: Instant,
+    ) -> Option<CacheObject> {
+        let serve_stale_configuration = self
+            .configuration
+            .cache_configuration()
+            .serve_stale_configuration()?;
 
-        let now = Instant::now();
+        let stale_window =
+            Duration::from_secs(serve_stale_configuration.stale_window_seconds());
 
-        if cache_object.expired(now) {
-            return None;
+        if cache_object.stale_expired(now, stale_window) {
+            None
+        } else {
+            Some(cache_object)
         }
+    }
 
-        let seconds_to_subtract_from_ttl = cache_object.duration_in_cache(now).as_secs();
+    // Serves the stale cache object if there is one, otherwise SERVFAIL.  RFC 8767 section 4:
+    // stale records are returned with a short TTL.
+    fn build_stale_or_failure_response_buffer(
+        &self,
+        request_message: &Message,
+        stale_cache_object: Option<CacheObject>,
+    ) -> Option<Vec<u8>> {
+        let serve_stale_configuration = self
+            .configuration
+            .cache_configuration()
+            .serve_stale_configuration();
+
+        match (stale_cache_object, serve_stale_configuration) {
+            (Some(stale_cache_object), Some(serve_stale_configuration)) => {
+                debug!("serving stale response");
+                self.metrics.counter_metric(CounterMetricType::StaleResponses).increment_value();
+                Some(stale_cache_object.message_buffer_with_ttl(
+                    serve_stale_configuration.stale_ttl_seconds(),
+                    request_message.header().id(),
+                ))
+            }
+            _ => self.build_failure_response_buffer(request_message),
+        }
+    }
 
-        let adjust_record_ttl = |record: Record| -> Option<Record> {
-            let original_ttl = u64::from(record.ttl());
+    fn encode_response_message(
+        &self,
+        request_message: &Message,
+        response_message: Message,
+    ) -> Option<Vec<u8>> {
+        match utils::encode_dns_message(response_message) {
+            Err(e) => {
+                warn!("encode_dns_message response error {}", e);
+                self.build_failure_response_buffer(request_message)
+            }
+            Ok(buffer) => Some(buffer),
+        }
+    }
 
-            if seconds_to_subtract_from_ttl > original_ttl {
-                None
-            } else {
-                let new_ttl = original_ttl - seconds_to_subtract_from_ttl;
-                let new_ttl = match u32::try_from(new_ttl) {
-                    Ok(new_ttl) => new_ttl,
-                    Err(e) => {
-                        warn!(
-                            "get_message_for_cache_hit new_ttl overflow {} {}",
-                            new_ttl, e
-                        );
-                        return None;
-                    }
-                };
-                let mut record = record;
-                record.set_ttl(new_ttl);
-                Some(record)
+    // Refreshes a popular cache entry in the background before it expires.  Requests that miss
+    // the cache while the prefetch is in flight wait for its response.
+    async fn prefetch(self: Arc<Self>, request_message: Message, request_key: RequestKey) {
+        let in_flight_request_guard = match self.in_flight_requests.begin(&request_key) {
+            InFlightRequest::Waiter(_) => {
+                debug!("prefetch skipped, request already in flight");
+                return;
             }
+            InFlightRequest::Leader(in_flight_request_guard) => in_flight_request_guard,
         };
 
-        let mut cache_object = cache_object;
-        let response_message = cache_object.message_mut();
+        debug!("prefetch request_key = {:#?}", request_key);
+        self.metrics.counter_metric(CounterMetricType::Prefetches).increment_value();
 
-        for record in response_message.take_answers() {
-            response_message.add_answer(match adjust_record_ttl(record) {
-                None => return None,
-                Some(record) => record,
-            });
-        }
-        for record in response_message.take_name_servers() {
-            response_message.add_name_server(match adjust_record_ttl(record) {
-                None => return None,
-                Some(record) => record,
-            });
-        }
-        for record in response_message.take_additionals() {
-            response_message.add_additional(match adjust_record_ttl(record) {
-                None => return None,
-                Some(record) => record,
-            });
-        }
+        let forwarding_rule = self.forwarding_rules.find_rule(&request_key);
 
-        response_message.set_id(request_id);
+        let response_message = self
+            .make_doh_request(&request_message, forwarding_rule)
+            .await
+            .map(|doh_response| self.clamp_ttl_and_cache_response(request_key, doh_response));
+
+        let prefetch_failed = match response_message.as_ref() {
+            None => true,
+            Some(response_message) => response_message.response_code() == ResponseCode::ServFail,
+        };
+        if prefetch_failed {
+            self.metrics.counter_metric(CounterMetricType::PrefetchFailures).increment_value();
+        }
 
-        Some(cache_object.message())
+        in_flight_request_guard.complete(response_message.as_ref());
     }
 
-    async fn process_request_message(&self, request_message: &Message) -> Message {
+    async fn process_request_message(
+        self: &Arc<Self>,
+        request_message: &Message,
+    ) -> Option<Vec<u8>> {
         debug!(
             "process_request_message request_message {:#?}",
             request_message
@@ -266,7 +357,7 @@ impl DOHProxy {
             Ok(request_key) => request_key,
             Err(e) => {
                 warn!("request_key try_from error: {}", e);
-                return self.build_failure_response_message(request_message);
+                return self.build_failure_response_buffer(request_message);
             }
         };
 
@@ -277,36 +368,98 @@ impl DOHProxy {
         {
             debug!("local domain request");
             self.metrics.counter_metric(CounterMetricType::LocalRequests).increment_value();
-            return response_message;
+            return self.encode_response_message(request_message, response_message);
         }
 
-        if let Some(response_message) = self
-            .get_message_for_cache_hit(&request_key, request_message.header().id())
-            .await
-        {
-            debug!("cache hit");
-            self.metrics.counter_metric(CounterMetricType::CacheHits).increment_value();
-            return response_message;
+        let forwarding_rule = self.forwarding_rules.find_rule(&request_key);
+        if let Some(forwarding_rule) = forwarding_rule {
+            debug!(
+                "matched forwarding rule {} upstream {}",
+                forwarding_rule.domain_suffix(),
+                forwarding_rule.upstream_name()
+            );
+            forwarding_rule.increment_matches();
         }
 
+        let now = Instant::now();
+
+        let stale_cache_object = match self.cache.get(&request_key) {
+            None => None,
+            Some((cache_object, _)) if cache_object.expired(now) => {
+                self.get_stale_cache_object(cache_object, now)
+            }
+            Some((cache_object, prefetch)) => {
+                if prefetch {
+                    tokio::spawn(
+                        Arc::clone(self).prefetch(request_message.clone(), request_key.clone()),
+                    );
+                }
+                if let Some(response_buffer) =
+                    cache_object.message_buffer_for_hit(now, request_message.header().id())
+                {
+                    debug!("cache hit");
+                    self.metrics.counter_metric(CounterMetricType::CacheHits).increment_value();
+                    if cache_object.negative_response() {
+                        self.metrics.counter_metric(CounterMetricType::NegativeCacheHits).increment_value();
+                    }
+                    return Some(response_buffer);
+                }
+                None
+            }
+        };
+
         debug!("cache miss");
         self.metrics.counter_metric(CounterMetricType::CacheMisses).increment_value();
 
-        let response_message = match self.make_doh_request(request_message).await {
-            None => return self.build_failure_response_message(request_message),
-            Some(response_message) => response_message,
+        let response_message = match self.in_flight_requests.begin(&request_key) {
+            InFlightRequest::Waiter(receiver) => {
+                debug!("coalesced request");
+                self.metrics.counter_metric(CounterMetricType::CoalescedRequests).increment_value();
+                match receiver.await {
+                    Ok(Some(response_message)) => response_message,
+                    _ => {
+                        return self.build_stale_or_failure_response_buffer(
+                            request_message,
+                            stale_cache_object,
+                        )
+                    }
+                }
+            }
+            InFlightRequest::Leader(in_flight_request_guard) => {
+                let response_message = self
+                    .make_doh_request(request_message, forwarding_rule)
+                    .await
+                    .map(|doh_response| {
+                        self.clamp_ttl_and_cache_response(request_key, doh_response)
+                    });
+                in_flight_request_guard.complete(response_message.as_ref());
+                match response_message {
+                    None => {
+                        return self.build_stale_or_failure_response_buffer(
+                            request_message,
+                            stale_cache_object,
+                        )
+                    }
+                    Some(response_message) => response_message,
+                }
+            }
         };
 
-        let mut response_message = self
-            .clamp_ttl_and_cache_response(request_key, response_message)
-            .await;
+        // RFC 8767 section 5: an upstream SERVFAIL is also a failure to refresh.
+        if (response_message.response_code() == ResponseCode::ServFail)
+            && stale_cache_object.is_some()
+        {
+            return self.build_stale_or_failure_response_buffer(request_message, stale_cache_object);
+        }
+
+        let mut response_message = response_message;
         response_message.set_id(request_message.header().id());
 
-        response_message
+        self.encode_response_message(request_message, response_message)
     }
 
     pub(in crate::doh) async fn process_request_packet_buffer(
-        &self,
+        self: &Arc<Self>,
         request_buffer: Vec<u8>,
     ) -> Option<Vec<u8>> {
         debug!(
@@ -322,14 +475,49 @@ impl DOHProxy {
             Ok(message) => message,
         };
 
-        let response_message = self.process_request_message(&request_message).await;
+        self.process_request_message(&request_message).await
+    }
 
-        match utils::encode_dns_message(response_message) {
-            Err(e) => {
-                warn!("encode_dns_message response error {}", e);
-                self.build_failure_response_buffer(&request_message)
-            }
-            Ok(buffer) => Some(buffer),
+    async fn load_cache_snapshot(&self) {
+        let cache_snapshot_configuration = match self
+            .configuration
+            .cache_configuration()
+            .cache_snapshot_configuration()
+        {
+            None => return,
+            Some(cache_snapshot_configuration) => cache_snapshot_configuration,
+        };
+
+        let file_path = cache_snapshot_configuration.file_path();
+
+        match cachesnapshot::load_cache_snapshot(&self.cache, file_path).await {
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io_error) if io_error.kind() == std::io::ErrorKind::NotFound => {
+                    info!("no cache snapshot {}", file_path)
+                }
+                _ => warn!("error loading cache snapshot {}: {}", file_path, e),
+            },
+            Ok(entries) => info!("loaded {} cache snapshot entries from {}", entries, file_path),
+        }
+    }
+
+    async fn write_cache_snapshot(&self) {
+        let cache_snapshot_configuration = match self
+            .configuration
+            .cache_configuration()
+            .cache_snapshot_configuration()
+        {
+            None => return,
+            Some(cache_snapshot_configuration) => cache_snapshot_configuration,
+        };
+
+        let file_path = cache_snapshot_configuration.file_path();
+
+        let _cache_snapshot_guard = self.cache_snapshot_mutex.lock().await;
+
+        match cachesnapshot::write_cache_snapshot(&self.cache, file_path).await {
+            Err(e) => warn!("error writing cache snapshot {}: {}", file_path, e),
+            Ok(entries) => info!("wrote {} cache snapshot entries to {}", entries, file_path),
         }
     }
 
@@ -338,20 +526,61 @@ impl DOHProxy {
 
         let timer_duration = Duration::from_secs(self.configuration.timer_interval_seconds());
 
+        let cache_snapshot_interval = self
+            .configuration
+            .cache_configuration()
+            .cache_snapshot_configuration()
+            .map(|cache_snapshot_configuration| {
+                Duration::from_secs(cache_snapshot_configuration.snapshot_interval_seconds())
+            });
+        let mut last_cache_snapshot_time = Instant::now();
+
         loop {
             tokio::time::delay_for(timer_duration).await;
 
-            let (cache_len, cache_items_purged) = self.cache.periodic_purge().await;
+            let (cache_len, cache_items_purged) = self.cache.periodic_purge();
+
+            if let Some(cache_snapshot_interval) = cache_snapshot_interval {
+                if last_cache_snapshot_time.elapsed() >= cache_snapshot_interval {
+                    self.write_cache_snapshot().await;
+                    last_cache_snapshot_time = Instant::now();
+                }
+            }
+
+            self.doh_client.periodic_bootstrap_refresh();
 
-            info!("{} cache_len={} cache_items_purged={}",
+            self.doh_client.periodic_health_checks();
+
+            info!("{} cache_len={} cache_items_purged={} {} {} {} {}",
                   self.metrics.all_metrics_string(), cache_len, cache_items_purged,
+                  self.doh_client.selected_upstream_metrics_string(),
+                  self.doh_client.request_queue_metrics_string(),
+                  self.doh_client.retry_metrics_string(),
+                  self.forwarding_rules.metrics_string(),
             );
         }
     }
 
+    // SIGTERM from systemd or SIGINT.
+    async fn wait_for_shutdown_signal() -> std::io::Result<()> {
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            _ = sigterm.recv() => info!("got SIGTERM"),
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                info!("got SIGINT");
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn run(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
         info!("begin run");
 
+        self.load_cache_snapshot().await;
+
         tokio::spawn(Arc::clone(&self).run_periodic_timer());
 
         let tcp_server = crate::doh::tcpserver::TCPServer::new(
@@ -370,6 +599,265 @@ impl DOHProxy {
             Arc::clone(&self.metrics),
             Arc::clone(&self),
         );
-        udp_server.run().await
+
+        tokio::select! {
+            result = udp_server.run() => result,
+            result = Self::wait_for_shutdown_signal() => {
+                result?;
+                info!("shutting down");
+                self.write_cache_snapshot().await;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use trust_dns_proto::op::{MessageType, Query};
+    use trust_dns_proto::rr::rdata::SOA;
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    fn build_proxy_configuration() -> ProxyConfiguration {
+        serde_json::from_value(serde_json::json!({
+            "clamp_min_ttl_seconds": 60,
+            "clamp_max_ttl_seconds": 3600,
+            "negative_clamp_min_ttl_seconds": 10,
+            "negative_clamp_max_ttl_seconds": 300,
+        }))
+        .unwrap()
+    }
+
+    fn build_response_message(answer_ttls: &[u32]) -> Message {
+        let name = Name::from_ascii("example.com.").unwrap();
+
+        let mut response_message = Message::new();
+        response_message
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::A));
+        for (i, ttl) in answer_ttls.iter().enumerate() {
+            response_message.add_answer(Record::from_rdata(
+                name.clone(),
+                *ttl,
+                RData::A(Ipv4Addr::new(192, 0, 2, i as u8)),
+            ));
+        }
+
+        response_message
+    }
+
+    // Response with a CNAME record for each cname_ttl and, if soa is set, an SOA record with its
+    // (TTL, MINIMUM) in the authority section.
+    fn build_negative_response_message(
+        response_code: ResponseCode,
+        cname_ttls: &[u32],
+        soa: Option<(u32, u32)>,
+    ) -> Message {
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let zone = Name::from_ascii("example.com.").unwrap();
+
+        let mut response_message = Message::new();
+        response_message
+            .set_message_type(MessageType::Response)
+            .set_response_code(response_code)
+            .add_query(Query::query(name.clone(), RecordType::A));
+        for ttl in cname_ttls {
+            response_message.add_answer(Record::from_rdata(
+                name.clone(),
+                *ttl,
+                RData::CNAME(zone.clone()),
+            ));
+        }
+        if let Some((ttl, minimum)) = soa {
+            response_message.add_name_server(Record::from_rdata(
+                zone.clone(),
+                ttl,
+                RData::SOA(SOA::new(
+                    Name::from_ascii("ns.example.com.").unwrap(),
+                    Name::from_ascii("hostmaster.example.com.").unwrap(),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    minimum,
+                )),
+            ));
+        }
+
+        response_message
+    }
+
+    // The min TTL in seconds and the record TTLs.
+    fn clamp(
+        response_message: Message,
+        cache_control: Option<&str>,
+        age: Option<&str>,
+    ) -> (u64, Vec<u32>) {
+        let (min_ttl_duration, response_message) = clamp_and_get_min_ttl_duration(
+            &build_proxy_configuration(),
+            response_message,
+            &HTTPCacheHeaders::from_header_values(cache_control, age),
+        );
+
+        let record_ttls = response_message
+            .answers()
+            .iter()
+            .chain(response_message.name_servers())
+            .map(Record::ttl)
+            .collect();
+
+        (min_ttl_duration.as_secs(), record_ttls)
+    }
+
+    #[test]
+    fn clamp_without_http_cache_headers() {
+        assert_eq!(
+            clamp(build_response_message(&[300, 120]), None, None),
+            (120, vec![300, 120])
+        );
+        assert_eq!(
+            clamp(build_response_message(&[5, 7200]), None, None),
+            (60, vec![60, 3600])
+        );
+        assert_eq!(
+            clamp(build_response_message(&[0]), None, None),
+            (60, vec![60])
+        );
+    }
+
+    #[test]
+    fn clamp_max_age_below_min_clamp() {
+        // Upstreams that set max-age to the minimum record TTL must not override the min clamp.
+        assert_eq!(
+            clamp(build_response_message(&[30]), Some("max-age=30"), None),
+            (60, vec![60])
+        );
+        assert_eq!(
+            clamp(build_response_message(&[30]), Some("max-age=30"), Some("0")),
+            (60, vec![60])
+        );
+    }
+
+    #[test]
+    fn clamp_subtracts_age_before_clamping() {
+        assert_eq!(
+            clamp(
+                build_response_message(&[300, 600]),
+                Some("max-age=300"),
+                Some("100")
+            ),
+            (200, vec![200, 500])
+        );
+        assert_eq!(
+            clamp(
+                build_response_message(&[100]),
+                Some("max-age=100"),
+                Some("50")
+            ),
+            (60, vec![60])
+        );
+    }
+
+    #[test]
+    fn clamp_age_greater_than_ttl_is_not_cached() {
+        assert_eq!(
+            clamp(build_response_message(&[300, 30]), None, Some("40")).0,
+            0
+        );
+    }
+
+    #[test]
+    fn clamp_stale_http_response_is_not_cached() {
+        assert_eq!(
+            clamp(
+                build_response_message(&[3600]),
+                Some("max-age=300"),
+                Some("300")
+            )
+            .0,
+            0
+        );
+    }
+
+    #[test]
+    fn clamp_negative_response_uses_negative_clamps() {
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NXDomain, &[], Some((3600, 3600))),
+                None,
+                None
+            ),
+            (300, vec![300])
+        );
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NXDomain, &[], Some((5, 5))),
+                None,
+                None
+            ),
+            (10, vec![10])
+        );
+    }
+
+    #[test]
+    fn clamp_negative_response_caps_soa_ttl_at_minimum() {
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NXDomain, &[], Some((3600, 120))),
+                None,
+                None
+            ),
+            (120, vec![120])
+        );
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NoError, &[], Some((100, 200))),
+                None,
+                None
+            ),
+            (100, vec![100])
+        );
+    }
+
+    #[test]
+    fn clamp_cname_only_response_is_negative() {
+        assert_eq!(
+            clamp(
+                build_negative_response_message(
+                    ResponseCode::NoError,
+                    &[3600, 3600],
+                    Some((3600, 120))
+                ),
+                None,
+                None
+            ),
+            (120, vec![300, 300, 120])
+        );
+    }
+
+    #[test]
+    fn clamp_negative_response_without_soa_is_not_cached() {
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NXDomain, &[], None),
+                None,
+                None
+            )
+            .0,
+            0
+        );
+        assert_eq!(
+            clamp(
+                build_negative_response_message(ResponseCode::NoError, &[3600], None),
+                None,
+                None
+            )
+            .0,
+            0
+        );
     }
 }
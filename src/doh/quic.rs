@@ -0,0 +1,465 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::client::WebPkiServerVerifier;
+use quinn::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+};
+use quinn::rustls::{
+    AlertDescription, CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::doh::client::{DOHRequestError, DOHRequestErrorType};
+use crate::doh::config::TLSConfiguration;
+use crate::doh::connector::UpstreamConnector;
+use crate::doh::tls;
+
+pub type QUICError = Box<dyn Error + Send + Sync>;
+
+// quinn needs a tokio 1 runtime, so QUIC connections run on their own runtime thread separate
+// from the tokio 0.2 runtime used everywhere else.
+fn quic_runtime() -> &'static quic_tokio::runtime::Runtime {
+    static QUIC_RUNTIME: OnceLock<quic_tokio::runtime::Runtime> = OnceLock::new();
+
+    QUIC_RUNTIME.get_or_init(|| {
+        quic_tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("quic")
+            .enable_all()
+            .build()
+            .expect("error building quic runtime")
+    })
+}
+
+// Aborts the QUIC runtime task if the caller stops waiting for it, e.g. on an upstream
+// request timeout.
+struct AbortOnDrop(quic_tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Runs future on the QUIC runtime and waits for it from the calling runtime.
+pub async fn run<F, T>(future: F) -> Result<T, QUICError>
+where
+    F: Future<Output = Result<T, QUICError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let join_handle = quic_runtime().spawn(future);
+    let _abort_on_drop = AbortOnDrop(join_handle.abort_handle());
+
+    match join_handle.await {
+        Ok(result) => result,
+        Err(e) => Err(format!("quic task error {}", e).into()),
+    }
+}
+
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    quic_runtime().spawn(future);
+}
+
+// Same checks as tls::SPKIPinningVerifier for the rustls version used by quinn.
+#[derive(Debug)]
+struct SPKIPinningVerifier {
+    webpki_verifier: Arc<WebPkiServerVerifier>,
+    spki_sha256_pins: Vec<String>,
+}
+
+impl ServerCertVerifier for SPKIPinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, quinn::rustls::Error> {
+        let server_cert_verified = self.webpki_verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let pin_matched = tls::verified_chain_matches_spki_pin(
+            &self.spki_sha256_pins,
+            end_entity,
+            intermediates.iter().map(|certificate| certificate.as_ref()),
+            |intermediate_index| {
+                let mut remaining_intermediates = intermediates.to_vec();
+                remaining_intermediates.remove(intermediate_index);
+                self.webpki_verifier
+                    .verify_server_cert(
+                        end_entity,
+                        &remaining_intermediates,
+                        server_name,
+                        ocsp_response,
+                        now,
+                    )
+                    .is_ok()
+            },
+        );
+
+        if pin_matched {
+            Ok(server_cert_verified)
+        } else {
+            warn!("spki pin validation failed for {}", server_name.to_str());
+            // Sent as an access_denied alert, which map_quic_error looks for.
+            Err(quinn::rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, quinn::rustls::Error> {
+        self.webpki_verifier
+            .verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, quinn::rustls::Error> {
+        self.webpki_verifier
+            .verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki_verifier.supported_verify_schemes()
+    }
+}
+
+fn read_client_private_key(
+    client_private_key_file: &str,
+) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let (private_key, pkcs8) = tls::read_private_key_file(client_private_key_file)?;
+
+    Ok(if pkcs8 {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(private_key.0))
+    } else {
+        PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(private_key.0))
+    })
+}
+
+// Builds the TLS 1.3 client config for QUIC from the same TLSConfiguration as
+// tls::build_tls_config.
+pub fn build_quic_client_config(
+    tls_configuration: Option<&TLSConfiguration>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<quinn::ClientConfig, Box<dyn Error>> {
+    let crypto_provider = Arc::new(quinn::rustls::crypto::ring::default_provider());
+
+    let mut root_store = RootCertStore::empty();
+    root_store.extend(quic_webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let mut spki_sha256_pins = Vec::new();
+    let mut client_certificate = None;
+
+    if let Some(tls_configuration) = tls_configuration {
+        for root_certificate_file in tls_configuration.root_certificate_files() {
+            let (valid_count, invalid_count) = root_store.add_parsable_certificates(
+                tls::read_certificate_file(root_certificate_file)?
+                    .into_iter()
+                    .map(|certificate| CertificateDer::from(certificate.0)),
+            );
+            info!(
+                "added quic root certificates from {} valid_count={} invalid_count={}",
+                root_certificate_file, valid_count, invalid_count
+            );
+            if valid_count == 0 {
+                return Err(
+                    format!("no valid root certificates in {}", root_certificate_file).into(),
+                );
+            }
+        }
+
+        spki_sha256_pins = tls_configuration.spki_sha256_pins().to_vec();
+
+        match (
+            tls_configuration.client_certificate_file(),
+            tls_configuration.client_private_key_file(),
+        ) {
+            (None, None) => {}
+            (Some(client_certificate_file), Some(client_private_key_file)) => {
+                let cert_chain = tls::read_certificate_file(client_certificate_file)?
+                    .into_iter()
+                    .map(|certificate| CertificateDer::from(certificate.0))
+                    .collect::<Vec<_>>();
+                client_certificate = Some((
+                    cert_chain,
+                    read_client_private_key(client_private_key_file)?,
+                ));
+            }
+            _ => {
+                return Err(
+                    "client_certificate_file and client_private_key_file must be set together"
+                        .into(),
+                )
+            }
+        }
+    }
+
+    let builder = quinn::rustls::ClientConfig::builder_with_provider(Arc::clone(&crypto_provider))
+        .with_protocol_versions(&[&quinn::rustls::version::TLS13])?;
+
+    let builder = if spki_sha256_pins.is_empty() {
+        builder.with_root_certificates(root_store)
+    } else {
+        let webpki_verifier = WebPkiServerVerifier::builder_with_provider(
+            Arc::new(root_store),
+            Arc::clone(&crypto_provider),
+        )
+        .build()?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SPKIPinningVerifier {
+                webpki_verifier,
+                spki_sha256_pins,
+            }))
+    };
+
+    let mut tls_config = match client_certificate {
+        None => builder.with_no_client_auth(),
+        Some((cert_chain, private_key)) => {
+            builder.with_client_auth_cert(cert_chain, private_key)?
+        }
+    };
+    tls_config.alpn_protocols = alpn_protocols;
+
+    Ok(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls_config)?,
+    )))
+}
+
+// Maps QUIC handshake failures caused by SPKIPinningVerifier to
+// DOHRequestErrorType::SPKIPinValidationFailed.  quinn reports a local certificate verification
+// failure as a transport error with the TLS alert as its code, and webpki never fails with
+// access_denied itself.
+pub fn map_quic_error(error: QUICError) -> Box<dyn Error> {
+    let spki_pin_validation_failed_code =
+        quinn::TransportErrorCode::crypto(AlertDescription::AccessDenied.into());
+
+    match error.downcast_ref::<quinn::ConnectionError>() {
+        Some(quinn::ConnectionError::TransportError(transport_error))
+            if transport_error.code == spki_pin_validation_failed_code =>
+        {
+            DOHRequestError::new(DOHRequestErrorType::SPKIPinValidationFailed)
+        }
+        _ => error,
+    }
+}
+
+async fn connect_address(
+    client_config: quinn::ClientConfig,
+    socket_address: SocketAddr,
+    server_name: &str,
+    connect_timeout: Duration,
+) -> Result<(quinn::Endpoint, quinn::Connection), QUICError> {
+    let bind_address: SocketAddr = if socket_address.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+
+    let mut endpoint = quinn::Endpoint::client(bind_address)?;
+    endpoint.set_default_client_config(client_config);
+
+    let connecting = endpoint.connect(socket_address, server_name)?;
+
+    let connection = quic_tokio::time::timeout(connect_timeout, connecting)
+        .await
+        .map_err(|_| format!("quic connect timeout after {:?}", connect_timeout))??;
+
+    Ok((endpoint, connection))
+}
+
+// Resolves host with the UpstreamConnector's BootstrapResolver and connects to the first
+// address that answers.  The endpoint must be kept with the connection.
+pub async fn connect(
+    upstream_connector: &UpstreamConnector,
+    client_config: &quinn::ClientConfig,
+    host: &str,
+    port: u16,
+    connect_timeout: Duration,
+) -> Result<(quinn::Endpoint, quinn::Connection), QUICError> {
+    let socket_addresses = upstream_connector.resolve(host, port).await?;
+
+    let client_config = client_config.clone();
+    let host = host.to_string();
+
+    run(async move {
+        let mut last_error = None;
+
+        for socket_address in socket_addresses {
+            match connect_address(
+                client_config.clone(),
+                socket_address,
+                &host,
+                connect_timeout,
+            )
+            .await
+            {
+                Ok(endpoint_and_connection) => {
+                    debug!("quic connected to {} at {}", host, socket_address);
+                    return Ok(endpoint_and_connection);
+                }
+                Err(e) => {
+                    debug!("quic connect to {} at {} error {}", host, socket_address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| format!("no addresses found for {}", host).into()))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::doh::testutil::{self, TestCertificate, TestPKI, TEST_DNS_NAME};
+
+    // Connects to a new server presenting cert_chain, trusting only the root of trusted_pki.
+    async fn connect_to_server(
+        trusted_pki: &TestPKI,
+        spki_sha256_pins: Vec<String>,
+        cert_chain: &[&TestCertificate],
+    ) -> Result<(), Box<dyn Error>> {
+        let endpoint =
+            testutil::start_quic_server(testutil::build_quic_server_config(cert_chain, b"doq"), 0)
+                .await;
+        let server_address = endpoint.local_addr().unwrap();
+
+        spawn(async move {
+            let mut connections = Vec::new();
+            while let Some(incoming) = endpoint.accept().await {
+                if let Ok(connection) = incoming.await {
+                    connections.push(connection);
+                }
+            }
+        });
+
+        let root_certificate_file = trusted_pki.root_certificate_file();
+        let tls_configuration: TLSConfiguration = serde_json::from_value(
+            testutil::tls_configuration_json(&root_certificate_file, spki_sha256_pins),
+        )
+        .unwrap();
+        let client_config =
+            build_quic_client_config(Some(&tls_configuration), vec![b"doq".to_vec()])?;
+
+        run(async move {
+            connect_address(
+                client_config,
+                server_address,
+                TEST_DNS_NAME,
+                Duration::from_secs(5),
+            )
+            .await
+        })
+        .await
+        .map(|_| ())
+        .map_err(map_quic_error)
+    }
+
+    fn is_spki_pin_validation_error(error: &dyn Error) -> bool {
+        error.to_string()
+            == DOHRequestError::new(DOHRequestErrorType::SPKIPinValidationFailed).to_string()
+    }
+
+    #[tokio::test]
+    async fn connect_leaf_pin() {
+        let pki = TestPKI::new();
+
+        connect_to_server(&pki, vec![pki.leaf.pin()], &[&pki.leaf, &pki.intermediate])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_intermediate_pin() {
+        let pki = TestPKI::new();
+
+        connect_to_server(
+            &pki,
+            vec![pki.intermediate.pin()],
+            &[&pki.leaf, &pki.intermediate],
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_pin_mismatch() {
+        let pki = TestPKI::new();
+
+        let error = connect_to_server(
+            &pki,
+            vec![pki.leaf_signed_by_root.pin()],
+            &[&pki.leaf, &pki.intermediate],
+        )
+        .await
+        .unwrap_err();
+        assert!(is_spki_pin_validation_error(error.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn connect_pinned_certificate_outside_verified_chain() {
+        let pki = TestPKI::new();
+
+        // The chain is valid without the pinned intermediate, so presenting it is not enough.
+        let error = connect_to_server(
+            &pki,
+            vec![pki.intermediate.pin()],
+            &[&pki.leaf_signed_by_root, &pki.intermediate],
+        )
+        .await
+        .unwrap_err();
+        assert!(is_spki_pin_validation_error(error.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn connect_chain_error_is_not_pin_error() {
+        let pki = TestPKI::new();
+        let other_pki = TestPKI::new();
+
+        let error = connect_to_server(
+            &other_pki,
+            vec![pki.leaf.pin()],
+            &[&pki.leaf, &pki.intermediate],
+        )
+        .await
+        .unwrap_err();
+        assert!(!is_spki_pin_validation_error(error.as_ref()));
+    }
+
+    #[test]
+    fn map_quic_error_keeps_other_errors() {
+        let error = map_quic_error("quic connect timeout".into());
+
+        assert_eq!(error.to_string(), "quic connect timeout");
+    }
+}
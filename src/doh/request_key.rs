@@ -16,6 +16,14 @@ pub struct RequestKey {
     query_keys: Vec<RequestQueryKey>,
 }
 
+impl RequestKey {
+    // Lowercase fully qualified name of the query that sorts first.  Query keys are sorted, so
+    // for a message with several queries this may not be the message's first query.
+    pub fn first_name(&self) -> &str {
+        &self.query_keys[0].name
+    }
+}
+
 impl TryFrom<&Message> for RequestKey {
     type Error = &'static str;
 
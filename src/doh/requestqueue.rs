@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::doh::client::{DOHRequestError, DOHRequestErrorType};
+use crate::doh::config::RequestQueueConfiguration;
+
+// Decrements the queue depth when a waiting request gets a permit, times out, or is dropped.
+struct QueueDepthGuard<'a> {
+    queue_depth: &'a AtomicUsize,
+}
+
+impl Drop for QueueDepthGuard<'_> {
+    fn drop(&mut self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Limits outstanding upstream requests.  When request_queue_configuration is set, requests
+// over the limit wait for a permit in a bounded queue instead of failing immediately.
+pub struct RequestQueue {
+    semaphore: Semaphore,
+    max_queue_size: usize,
+    max_wait_duration: Duration,
+    queue_depth: AtomicUsize,
+    max_queue_depth: AtomicUsize,
+    queued_requests: AtomicU64,
+    queue_full_errors: AtomicU64,
+    queue_timeouts: AtomicU64,
+    total_wait_microseconds: AtomicU64,
+}
+
+impl RequestQueue {
+    pub fn new(
+        max_outstanding_requests: usize,
+        request_queue_configuration: Option<&RequestQueueConfiguration>,
+    ) -> Self {
+        let (max_queue_size, max_wait_duration) = match request_queue_configuration {
+            None => (0, Duration::from_secs(0)),
+            Some(request_queue_configuration) => (
+                request_queue_configuration.max_queue_size(),
+                Duration::from_millis(request_queue_configuration.max_wait_milliseconds()),
+            ),
+        };
+
+        RequestQueue {
+            semaphore: Semaphore::new(max_outstanding_requests),
+            max_queue_size,
+            max_wait_duration,
+            queue_depth: AtomicUsize::new(0),
+            max_queue_depth: AtomicUsize::new(0),
+            queued_requests: AtomicU64::new(0),
+            queue_full_errors: AtomicU64::new(0),
+            queue_timeouts: AtomicU64::new(0),
+            total_wait_microseconds: AtomicU64::new(0),
+        }
+    }
+
+    fn enter_queue(&self) -> Option<QueueDepthGuard<'_>> {
+        let queue_depth = self.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let queue_depth_guard = QueueDepthGuard {
+            queue_depth: &self.queue_depth,
+        };
+
+        if queue_depth > self.max_queue_size {
+            return None;
+        }
+
+        self.max_queue_depth
+            .fetch_max(queue_depth, Ordering::Relaxed);
+
+        Some(queue_depth_guard)
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, Box<DOHRequestError>> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Ok(permit);
+        }
+
+        let _queue_depth_guard = match self.enter_queue() {
+            None => {
+                self.queue_full_errors.fetch_add(1, Ordering::Relaxed);
+                return Err(DOHRequestError::new(
+                    DOHRequestErrorType::TooManyOutstandingRequests,
+                ));
+            }
+            Some(queue_depth_guard) => queue_depth_guard,
+        };
+
+        self.queued_requests.fetch_add(1, Ordering::Relaxed);
+
+        let start_time = Instant::now();
+
+        let result = tokio::time::timeout(self.max_wait_duration, self.semaphore.acquire()).await;
+
+        let wait_duration = start_time.elapsed();
+        self.total_wait_microseconds
+            .fetch_add(wait_duration.as_micros() as u64, Ordering::Relaxed);
+
+        match result {
+            Ok(permit) => {
+                debug!("acquired permit after waiting {:?}", wait_duration);
+                Ok(permit)
+            }
+            Err(_) => {
+                warn!("request queue wait timeout after {:?}", wait_duration);
+                self.queue_timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(DOHRequestError::new(
+                    DOHRequestErrorType::RequestQueueTimeout,
+                ))
+            }
+        }
+    }
+
+    pub fn metrics_string(&self) -> String {
+        let queued_requests = self.queued_requests.load(Ordering::Relaxed);
+        let average_wait_milliseconds = if queued_requests == 0 {
+            0.0
+        } else {
+            (self.total_wait_microseconds.load(Ordering::Relaxed) as f64)
+                / (queued_requests as f64)
+                / 1_000.0
+        };
+
+        format!(
+            "request_queue_depth={} request_queue_max_depth={} request_queue_queued={} request_queue_full={} request_queue_timeouts={} request_queue_average_wait_ms={:.3}",
+            self.queue_depth.load(Ordering::Relaxed),
+            self.max_queue_depth.load(Ordering::Relaxed),
+            queued_requests,
+            self.queue_full_errors.load(Ordering::Relaxed),
+            self.queue_timeouts.load(Ordering::Relaxed),
+            average_wait_milliseconds,
+        )
+    }
+}
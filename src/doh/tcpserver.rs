@@ -1,14 +1,13 @@
-use std::convert::TryFrom;
 use std::error::Error;
 use std::sync::Arc;
 
 use log::{debug, info, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
 use crate::doh::config::ServerConfiguration;
 use crate::doh::metrics::{CounterMetricType, Metrics};
 use crate::doh::proxy::DOHProxy;
+use crate::doh::utils;
 
 pub struct TCPServer {
     server_configuration: ServerConfiguration,
@@ -34,18 +33,13 @@ impl TCPServer {
         mut stream: TcpStream,
     ) -> Result<(), Box<dyn Error>> {
         loop {
-            let mut buffer = [0u8; 2];
-            stream.read_exact(&mut buffer).await?;
-            let length = u16::from_be_bytes(buffer);
+            let buffer = utils::read_tcp_message(&mut stream).await?;
 
-            if length == 0 {
+            if buffer.is_empty() {
                 warn!("read 0 length tcp header");
                 break;
             }
 
-            let mut buffer = vec![0u8; usize::from(length)];
-            stream.read_exact(&mut buffer).await?;
-
             self.metrics.counter_metric(CounterMetricType::TCPRequests).increment_value();
 
             let buffer = match self.doh_proxy.process_request_packet_buffer(buffer).await {
@@ -56,16 +50,7 @@ impl TCPServer {
                 }
             };
 
-            let length = match u16::try_from(buffer.len()) {
-                Ok(len) => len,
-                Err(e) => {
-                    warn!("response buffer.len overflow {}: {}", buffer.len(), e);
-                    break;
-                }
-            };
-
-            stream.write_all(&length.to_be_bytes()).await?;
-            stream.write_all(&buffer).await?;
+            utils::write_tcp_message(&mut stream, &buffer).await?;
         }
 
         Ok(())
@@ -0,0 +1,524 @@
+// Certificates and local DoH servers for unit tests.
+
+use std::convert::Infallible;
+#[cfg(feature = "quic")]
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::service::service_fn;
+use hyper::{header, Body};
+#[cfg(feature = "quic")]
+use quic_bytes::{Buf, Bytes};
+#[cfg(feature = "quic")]
+use quinn::crypto::rustls::QuicServerConfig;
+#[cfg(feature = "quic")]
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::doh::bootstrap::BootstrapResolver;
+use crate::doh::client::DOH_MIME_TYPE;
+use crate::doh::config::{ClientConfiguration, TLSConfiguration};
+use crate::doh::connector::UpstreamConnector;
+#[cfg(feature = "quic")]
+use crate::doh::quic::{self, QUICError};
+use crate::doh::tls;
+use crate::doh::utils;
+
+// Subject alternative name of the leaf certificates.
+pub const TEST_DNS_NAME: &str = "upstream.test";
+
+pub struct TestCertificate {
+    certificate: rcgen::Certificate,
+    key_pair: KeyPair,
+}
+
+impl TestCertificate {
+    fn new(common_name: &str, is_ca: bool, issuer: Option<&TestCertificate>) -> Self {
+        let subject_alt_names = if is_ca {
+            Vec::new()
+        } else {
+            vec![TEST_DNS_NAME.to_string()]
+        };
+        let mut params = CertificateParams::new(subject_alt_names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+
+        let key_pair = KeyPair::generate().unwrap();
+        let certificate = match issuer {
+            None => params.self_signed(&key_pair).unwrap(),
+            Some(issuer) => params
+                .signed_by(&key_pair, &issuer.certificate, &issuer.key_pair)
+                .unwrap(),
+        };
+
+        TestCertificate {
+            certificate,
+            key_pair,
+        }
+    }
+
+    pub fn der(&self) -> Vec<u8> {
+        self.certificate.der().to_vec()
+    }
+
+    pub fn pin(&self) -> String {
+        tls::spki_sha256_pin(&self.der()).unwrap()
+    }
+
+    pub fn public_key_der(&self) -> Vec<u8> {
+        self.key_pair.public_key_der()
+    }
+
+    // PKCS#8 DER.
+    pub fn private_key_der(&self) -> Vec<u8> {
+        self.key_pair.serialize_der()
+    }
+
+    fn pem(&self) -> String {
+        let base64_der = base64::encode(self.der());
+        let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+        for line in base64_der.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+        pem
+    }
+}
+
+// leaf is issued by intermediate, which is issued by root.  leaf_signed_by_root skips the
+// intermediate.
+pub struct TestPKI {
+    pub root: TestCertificate,
+    pub intermediate: TestCertificate,
+    pub leaf: TestCertificate,
+    pub leaf_signed_by_root: TestCertificate,
+}
+
+impl TestPKI {
+    pub fn new() -> Self {
+        let root = TestCertificate::new("root", true, None);
+        let intermediate = TestCertificate::new("intermediate", true, Some(&root));
+        let leaf = TestCertificate::new("leaf", false, Some(&intermediate));
+        let leaf_signed_by_root = TestCertificate::new("other leaf", false, Some(&root));
+        TestPKI {
+            root,
+            intermediate,
+            leaf,
+            leaf_signed_by_root,
+        }
+    }
+
+    pub fn root_certificate_file(&self) -> TestFile {
+        TestFile::new(self.root.pem().as_bytes())
+    }
+}
+
+// tls_configuration trusting only the roots in root_certificate_file.
+pub fn tls_configuration_json(
+    root_certificate_file: &TestFile,
+    spki_sha256_pins: Vec<String>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "root_certificate_files": [root_certificate_file.path()],
+        "spki_sha256_pins": spki_sha256_pins,
+    })
+}
+
+pub fn build_tls_configuration(root_certificate_file: &TestFile) -> TLSConfiguration {
+    serde_json::from_value(tls_configuration_json(root_certificate_file, Vec::new())).unwrap()
+}
+
+// Upstream configuration named name at url, with the upstream host resolving to 127.0.0.1 and
+// upstream_fields added.
+pub fn upstream_configuration_json(
+    name: &str,
+    url: &str,
+    upstream_fields: serde_json::Value,
+) -> serde_json::Value {
+    let mut upstream_configuration = serde_json::json!({
+        "name": name,
+        "url": url,
+        "request_timeout_seconds": 5,
+        "bootstrap_ip_addresses": ["127.0.0.1"],
+    });
+    upstream_configuration
+        .as_object_mut()
+        .unwrap()
+        .extend(upstream_fields.as_object().unwrap().clone());
+    upstream_configuration
+}
+
+// Client configuration with upstream_configurations and client_fields added.
+pub fn build_client_configuration_with_upstreams(
+    upstream_configurations: Vec<serde_json::Value>,
+    client_fields: serde_json::Value,
+) -> ClientConfiguration {
+    let mut client_configuration = serde_json::json!({
+        "upstream_configurations": upstream_configurations,
+        "max_outstanding_requests": 10,
+    });
+    client_configuration
+        .as_object_mut()
+        .unwrap()
+        .extend(client_fields.as_object().unwrap().clone());
+
+    serde_json::from_value(client_configuration).unwrap()
+}
+
+// Client configuration with one upstream at url.
+pub fn build_client_configuration(
+    url: &str,
+    upstream_fields: serde_json::Value,
+) -> ClientConfiguration {
+    build_client_configuration_with_upstreams(
+        vec![upstream_configuration_json("test", url, upstream_fields)],
+        serde_json::json!({}),
+    )
+}
+
+// Connector for a client of the test upstream at url, without an egress proxy.
+pub fn build_upstream_connector(url: &str) -> UpstreamConnector {
+    let client_configuration = build_client_configuration(url, serde_json::json!({}));
+    UpstreamConnector::new(
+        Arc::new(BootstrapResolver::new(&client_configuration).unwrap()),
+        None,
+    )
+}
+
+// Query for example.com A.
+pub fn build_request_message() -> Message {
+    let mut request_message = Message::new();
+    request_message
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+    request_message
+}
+
+// Temporary file removed on drop.
+pub struct TestFile(PathBuf);
+
+impl TestFile {
+    pub fn new(contents: &[u8]) -> Self {
+        static FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "rust-doh-proxy-test-{}-{}",
+            std::process::id(),
+            FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, contents).unwrap();
+
+        TestFile(path)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TestFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(feature = "quic")]
+// Server config presenting cert_chain, the first certificate being the end-entity.
+pub fn build_quic_server_config(
+    cert_chain: &[&TestCertificate],
+    alpn_protocol: &[u8],
+) -> quinn::ServerConfig {
+    let mut tls_config = quinn::rustls::ServerConfig::builder_with_provider(Arc::new(
+        quinn::rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&quinn::rustls::version::TLS13])
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+        cert_chain
+            .iter()
+            .map(|certificate| CertificateDer::from(certificate.der()))
+            .collect(),
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert_chain[0].private_key_der())),
+    )
+    .unwrap();
+    tls_config.alpn_protocols = vec![alpn_protocol.to_vec()];
+
+    quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config).unwrap()))
+}
+
+#[cfg(feature = "quic")]
+// Starts a QUIC server on the QUIC runtime.  udp_port 0 picks any free port.
+pub async fn start_quic_server(
+    server_config: quinn::ServerConfig,
+    udp_port: u16,
+) -> quinn::Endpoint {
+    quic::run(async move {
+        Ok(quinn::Endpoint::server(
+            server_config,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, udp_port)),
+        )?)
+    })
+    .await
+    .unwrap()
+}
+
+// Response to the request in request_buffer with an A record for each query.
+pub fn build_response_buffer(request_buffer: &[u8]) -> Vec<u8> {
+    let request_message = utils::decode_dns_message(request_buffer.to_vec()).unwrap();
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true);
+    for query in request_message.queries() {
+        response_message.add_query(query.clone());
+        response_message.add_answer(Record::from_rdata(
+            query.name().clone(),
+            60,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+    }
+
+    utils::encode_dns_message(response_message).unwrap()
+}
+
+// TLS over TCP acceptor presenting the TestPKI leaf and intermediate certificates.
+pub fn build_tls_acceptor(
+    test_pki: &TestPKI,
+    alpn_protocols: &[&[u8]],
+) -> tokio_rustls::TlsAcceptor {
+    let mut tls_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    tls_config
+        .set_single_cert(
+            vec![
+                rustls::Certificate(test_pki.leaf.der()),
+                rustls::Certificate(test_pki.intermediate.der()),
+            ],
+            rustls::PrivateKey(test_pki.leaf.private_key_der()),
+        )
+        .unwrap();
+    tls_config.set_protocols(
+        &alpn_protocols
+            .iter()
+            .map(|alpn_protocol| alpn_protocol.to_vec())
+            .collect::<Vec<_>>(),
+    );
+    tokio_rustls::TlsAcceptor::from(Arc::new(tls_config))
+}
+
+// DoH server using the TestPKI leaf certificate, with HTTPS over TCP and optionally HTTP/3 on
+// the same port number.
+pub struct TestDOHServer {
+    address: SocketAddr,
+    response_status: AtomicU16,
+    response_delay_milliseconds: AtomicU64,
+    https_requests: AtomicUsize,
+    #[cfg(feature = "quic")]
+    http3_requests: AtomicUsize,
+    #[cfg(feature = "quic")]
+    http3_connections: AtomicUsize,
+}
+
+impl TestDOHServer {
+    pub async fn start(test_pki: &TestPKI) -> Arc<Self> {
+        let tls_acceptor = build_tls_acceptor(test_pki, &[b"h2", b"http/1.1"]);
+
+        let mut tcp_listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+
+        let test_doh_server = Arc::new(TestDOHServer {
+            address: tcp_listener.local_addr().unwrap(),
+            response_status: AtomicU16::new(200),
+            response_delay_milliseconds: AtomicU64::new(0),
+            https_requests: AtomicUsize::new(0),
+            #[cfg(feature = "quic")]
+            http3_requests: AtomicUsize::new(0),
+            #[cfg(feature = "quic")]
+            http3_connections: AtomicUsize::new(0),
+        });
+
+        let accepting_server = Arc::clone(&test_doh_server);
+        tokio::spawn(async move {
+            while let Ok((tcp_stream, _)) = tcp_listener.accept().await {
+                let tls_acceptor = tls_acceptor.clone();
+                let test_doh_server = Arc::clone(&accepting_server);
+                tokio::spawn(async move {
+                    let tls_stream = match tls_acceptor.accept(tcp_stream).await {
+                        Ok(tls_stream) => tls_stream,
+                        Err(_) => return,
+                    };
+                    let service = service_fn(move |request| {
+                        Arc::clone(&test_doh_server).handle_https_request(request)
+                    });
+                    let _ = hyper::server::conn::Http::new()
+                        .serve_connection(tls_stream, service)
+                        .await;
+                });
+            }
+        });
+
+        test_doh_server
+    }
+
+    pub fn url(&self) -> String {
+        format!(
+            "https://{}:{}/dns-query",
+            TEST_DNS_NAME,
+            self.address.port()
+        )
+    }
+
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    pub fn set_response_status(&self, status: u16) {
+        self.response_status.store(status, Ordering::SeqCst);
+    }
+
+    pub fn set_response_delay(&self, response_delay: Duration) {
+        self.response_delay_milliseconds
+            .store(response_delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn https_requests(&self) -> usize {
+        self.https_requests.load(Ordering::SeqCst)
+    }
+
+    fn request_buffer(query: Option<&str>, body: &[u8]) -> Vec<u8> {
+        match query.and_then(|query| query.strip_prefix("dns=")) {
+            Some(dns) => base64::decode_config(dns, base64::URL_SAFE_NO_PAD).unwrap(),
+            None => body.to_vec(),
+        }
+    }
+
+    fn response(&self, request_buffer: &[u8]) -> (u16, Vec<u8>) {
+        match self.response_status.load(Ordering::SeqCst) {
+            200 => (200, build_response_buffer(request_buffer)),
+            status => (status, Vec::new()),
+        }
+    }
+
+    async fn handle_https_request(
+        self: Arc<Self>,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Infallible> {
+        self.https_requests.fetch_add(1, Ordering::SeqCst);
+
+        let query = request.uri().query().map(str::to_string);
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(
+            self.response_delay_milliseconds.load(Ordering::SeqCst),
+        ))
+        .await;
+        let (status, response_buffer) =
+            self.response(&Self::request_buffer(query.as_deref(), &body));
+
+        Ok(hyper::Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, DOH_MIME_TYPE)
+            .body(Body::from(response_buffer))
+            .unwrap())
+    }
+}
+
+#[cfg(feature = "quic")]
+impl TestDOHServer {
+    pub async fn start_http3(self: &Arc<Self>, test_pki: &TestPKI) {
+        let endpoint = start_quic_server(
+            build_quic_server_config(&[&test_pki.leaf, &test_pki.intermediate], b"h3"),
+            self.address.port(),
+        )
+        .await;
+
+        let test_doh_server = Arc::clone(self);
+        quic::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                quic::spawn(Arc::clone(&test_doh_server).serve_http3_connection(incoming));
+            }
+        });
+    }
+
+    pub fn http3_requests(&self) -> usize {
+        self.http3_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn http3_connections(&self) -> usize {
+        self.http3_connections.load(Ordering::SeqCst)
+    }
+
+    async fn serve_http3_connection(self: Arc<Self>, incoming: quinn::Incoming) {
+        let connection = match incoming.await {
+            Ok(connection) => connection,
+            Err(_) => return,
+        };
+        self.http3_connections.fetch_add(1, Ordering::SeqCst);
+
+        let mut h3_connection: h3::server::Connection<_, Bytes> =
+            match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+                Ok(h3_connection) => h3_connection,
+                Err(_) => return,
+            };
+
+        while let Ok(Some(request_resolver)) = h3_connection.accept().await {
+            let test_doh_server = Arc::clone(&self);
+            quic::spawn(async move {
+                let _ = test_doh_server.handle_http3_request(request_resolver).await;
+            });
+        }
+    }
+
+    async fn handle_http3_request(
+        &self,
+        request_resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>,
+    ) -> Result<(), QUICError> {
+        self.http3_requests.fetch_add(1, Ordering::SeqCst);
+
+        let (request, mut request_stream) = request_resolver.resolve_request().await?;
+
+        let mut body = Vec::new();
+        while let Some(mut chunk) = request_stream.recv_data().await? {
+            while chunk.has_remaining() {
+                let chunk_length = chunk.chunk().len();
+                body.extend_from_slice(chunk.chunk());
+                chunk.advance(chunk_length);
+            }
+        }
+
+        let (status, response_buffer) =
+            self.response(&Self::request_buffer(request.uri().query(), &body));
+
+        request_stream
+            .send_response(
+                http::Response::builder()
+                    .status(status)
+                    .header(http::header::CONTENT_TYPE, DOH_MIME_TYPE)
+                    .body(())?,
+            )
+            .await?;
+        request_stream
+            .send_data(Bytes::from(response_buffer))
+            .await?;
+        request_stream.finish().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use log::{info, warn};
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    TLSError, WebPKIVerifier,
+};
+use tokio_rustls::webpki::DNSNameRef;
+
+use crate::doh::config::TLSConfiguration;
+use crate::doh::utils;
+
+const SPKI_PIN_VALIDATION_FAILED_MESSAGE: &str = "spki pin validation failed";
+
+// Returns the length of the DER header and the length of the contents.
+fn der_header_and_content_length(der: &[u8]) -> Option<(usize, usize)> {
+    let first_length_byte = *der.get(1)?;
+
+    if first_length_byte < 0x80 {
+        return Some((2, usize::from(first_length_byte)));
+    }
+
+    let length_bytes = usize::from(first_length_byte & 0x7f);
+    if (length_bytes == 0) || (length_bytes > 4) {
+        return None;
+    }
+
+    let mut content_length = 0usize;
+    for i in 0..length_bytes {
+        content_length = (content_length << 8) | usize::from(*der.get(2 + i)?);
+    }
+
+    Some((2 + length_bytes, content_length))
+}
+
+// Splits the first DER element off the front of der, returning (element, rest).
+fn split_der_element(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (header_length, content_length) = der_header_and_content_length(der)?;
+    let element_length = header_length.checked_add(content_length)?;
+    if element_length > der.len() {
+        None
+    } else {
+        Some(der.split_at(element_length))
+    }
+}
+
+fn der_element_contents(der: &[u8]) -> Option<&[u8]> {
+    let (header_length, _) = der_header_and_content_length(der)?;
+    Some(&der[header_length..])
+}
+
+// RFC 5280 section 4.1: returns the DER encoded subjectPublicKeyInfo of a certificate.
+fn certificate_spki(certificate_der: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = split_der_element(certificate_der)?;
+    let (tbs_certificate, _) = split_der_element(der_element_contents(certificate)?)?;
+    let mut rest = der_element_contents(tbs_certificate)?;
+
+    // Optional explicitly tagged version.
+    if rest.first() == Some(&0xa0) {
+        rest = split_der_element(rest)?.1;
+    }
+
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = split_der_element(rest)?.1;
+    }
+
+    let (spki, _) = split_der_element(rest)?;
+    Some(spki)
+}
+
+pub fn spki_sha256_pin(certificate_der: &[u8]) -> Option<String> {
+    let spki = certificate_spki(certificate_der)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+    Some(base64::encode(digest.as_ref()))
+}
+
+fn spki_pin_matches(spki_sha256_pins: &[String], certificate_der: &[u8]) -> bool {
+    match spki_sha256_pin(certificate_der) {
+        Some(pin) => spki_sha256_pins.contains(&pin),
+        None => false,
+    }
+}
+
+// True if the end-entity certificate, or a presented intermediate that the verified chain
+// depends on, matches one of the pins.  A pinned certificate the peer sends alongside an
+// otherwise valid chain does not count.  webpki does not return the chain it built, so an
+// intermediate is known to be in it when verifies_without_intermediate(index) fails.
+pub fn verified_chain_matches_spki_pin<'a>(
+    spki_sha256_pins: &[String],
+    end_entity_der: &[u8],
+    intermediate_ders: impl Iterator<Item = &'a [u8]>,
+    verifies_without_intermediate: impl Fn(usize) -> bool,
+) -> bool {
+    spki_pin_matches(spki_sha256_pins, end_entity_der)
+        || intermediate_ders
+            .enumerate()
+            .filter(|(_, intermediate_der)| spki_pin_matches(spki_sha256_pins, intermediate_der))
+            .any(|(index, _)| !verifies_without_intermediate(index))
+}
+
+// Runs normal webpki verification, then requires verified_chain_matches_spki_pin.
+struct SPKIPinningVerifier {
+    webpki_verifier: WebPKIVerifier,
+    spki_sha256_pins: Vec<String>,
+}
+
+impl ServerCertVerifier for SPKIPinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let server_cert_verified = self.webpki_verifier.verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        )?;
+
+        let pin_matched = verified_chain_matches_spki_pin(
+            &self.spki_sha256_pins,
+            &presented_certs[0].0,
+            presented_certs[1..]
+                .iter()
+                .map(|certificate| certificate.0.as_slice()),
+            |intermediate_index| {
+                let mut remaining_certs = presented_certs.to_vec();
+                remaining_certs.remove(intermediate_index + 1);
+                self.webpki_verifier
+                    .verify_server_cert(roots, &remaining_certs, dns_name, ocsp_response)
+                    .is_ok()
+            },
+        );
+
+        if pin_matched {
+            Ok(server_cert_verified)
+        } else {
+            let dns_name: &str = dns_name.into();
+            warn!("spki pin validation failed for {}", dns_name);
+            Err(TLSError::General(
+                SPKI_PIN_VALIDATION_FAILED_MESSAGE.to_string(),
+            ))
+        }
+    }
+}
+
+// Looks for a pin validation failure from SPKIPinningVerifier in the error source chain.
+pub fn is_spki_pin_validation_error(error: &(dyn Error + 'static)) -> bool {
+    utils::error_chain_contains(error, &|error| {
+        matches!(
+            error.downcast_ref::<TLSError>(),
+            Some(TLSError::General(message)) if message == SPKI_PIN_VALIDATION_FAILED_MESSAGE
+        )
+    })
+}
+
+fn open_file(file_name: &str) -> Result<BufReader<File>, Box<dyn Error>> {
+    let file = File::open(file_name).map_err(|e| format!("error opening {}: {}", file_name, e))?;
+    Ok(BufReader::new(file))
+}
+
+// Returns the DER encoded certificates in a PEM file.
+pub fn read_certificate_file(certificate_file: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    pemfile::certs(&mut open_file(certificate_file)?)
+        .map_err(|_| format!("invalid certificate file {}", certificate_file).into())
+}
+
+// Returns the first PKCS#8 or PKCS#1 (RSA) private key in a PEM file and whether it is PKCS#8.
+pub fn read_private_key_file(private_key_file: &str) -> Result<(PrivateKey, bool), Box<dyn Error>> {
+    let invalid_private_key_file = || format!("invalid private key file {}", private_key_file);
+
+    let private_keys = pemfile::pkcs8_private_keys(&mut open_file(private_key_file)?)
+        .map_err(|_| invalid_private_key_file())?;
+    if let Some(private_key) = private_keys.into_iter().next() {
+        return Ok((private_key, true));
+    }
+
+    let private_keys = pemfile::rsa_private_keys(&mut open_file(private_key_file)?)
+        .map_err(|_| invalid_private_key_file())?;
+    match private_keys.into_iter().next() {
+        Some(private_key) => Ok((private_key, false)),
+        None => Err(format!("no private key found in {}", private_key_file).into()),
+    }
+}
+
+fn set_client_certificate(
+    tls_config: &mut ClientConfig,
+    client_certificate_file: &str,
+    client_private_key_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let cert_chain = read_certificate_file(client_certificate_file)?;
+
+    let (private_key, _) = read_private_key_file(client_private_key_file)?;
+
+    tls_config.set_single_client_cert(cert_chain, private_key)?;
+
+    Ok(())
+}
+
+pub fn build_tls_config(
+    tls_configuration: Option<&TLSConfiguration>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+    let mut tls_config = ClientConfig::new();
+    tls_config.alpn_protocols = alpn_protocols;
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    let tls_configuration = match tls_configuration {
+        None => return Ok(Arc::new(tls_config)),
+        Some(tls_configuration) => tls_configuration,
+    };
+
+    for root_certificate_file in tls_configuration.root_certificate_files() {
+        let (valid_count, invalid_count) = tls_config
+            .root_store
+            .add_pem_file(&mut open_file(root_certificate_file)?)
+            .map_err(|_| format!("invalid root certificate file {}", root_certificate_file))?;
+        info!(
+            "added root certificates from {} valid_count={} invalid_count={}",
+            root_certificate_file, valid_count, invalid_count
+        );
+        if valid_count == 0 {
+            return Err(format!("no valid root certificates in {}", root_certificate_file).into());
+        }
+    }
+
+    match (
+        tls_configuration.client_certificate_file(),
+        tls_configuration.client_private_key_file(),
+    ) {
+        (None, None) => {}
+        (Some(client_certificate_file), Some(client_private_key_file)) => set_client_certificate(
+            &mut tls_config,
+            client_certificate_file,
+            client_private_key_file,
+        )?,
+        _ => {
+            return Err(
+                "client_certificate_file and client_private_key_file must be set together".into(),
+            )
+        }
+    }
+
+    if !tls_configuration.spki_sha256_pins().is_empty() {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SPKIPinningVerifier {
+                webpki_verifier: WebPKIVerifier::new(),
+                spki_sha256_pins: tls_configuration.spki_sha256_pins().to_vec(),
+            }));
+    }
+
+    Ok(Arc::new(tls_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::doh::testutil::{TestPKI, TEST_DNS_NAME};
+
+    fn verify(
+        pki: &TestPKI,
+        spki_sha256_pins: Vec<String>,
+        presented_certs: &[Vec<u8>],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(pki.root.der())).unwrap();
+
+        let verifier = SPKIPinningVerifier {
+            webpki_verifier: WebPKIVerifier::new(),
+            spki_sha256_pins,
+        };
+        verifier.verify_server_cert(
+            &roots,
+            &presented_certs
+                .iter()
+                .cloned()
+                .map(Certificate)
+                .collect::<Vec<_>>(),
+            DNSNameRef::try_from_ascii_str(TEST_DNS_NAME).unwrap(),
+            &[],
+        )
+    }
+
+    fn assert_pin_validation_error(result: Result<ServerCertVerified, TLSError>) {
+        match result {
+            Ok(_) => panic!("expected spki pin validation error"),
+            Err(error) => assert!(is_spki_pin_validation_error(&error)),
+        }
+    }
+
+    #[test]
+    fn der_header_and_content_length_short_form() {
+        assert_eq!(der_header_and_content_length(&[0x30, 0x05]), Some((2, 5)));
+        assert_eq!(der_header_and_content_length(&[0x30, 0x7f]), Some((2, 127)));
+    }
+
+    #[test]
+    fn der_header_and_content_length_long_form() {
+        assert_eq!(
+            der_header_and_content_length(&[0x30, 0x81, 0x80]),
+            Some((3, 128))
+        );
+        assert_eq!(
+            der_header_and_content_length(&[0x30, 0x82, 0x01, 0x00]),
+            Some((4, 256))
+        );
+    }
+
+    #[test]
+    fn der_header_and_content_length_invalid() {
+        assert_eq!(der_header_and_content_length(&[]), None);
+        assert_eq!(der_header_and_content_length(&[0x30]), None);
+        // Indefinite length and lengths over 4 bytes are not supported.
+        assert_eq!(der_header_and_content_length(&[0x30, 0x80]), None);
+        assert_eq!(
+            der_header_and_content_length(&[0x30, 0x85, 1, 2, 3, 4, 5]),
+            None
+        );
+        // Truncated length bytes.
+        assert_eq!(der_header_and_content_length(&[0x30, 0x82, 0x01]), None);
+    }
+
+    #[test]
+    fn certificate_spki_matches_key_pair() {
+        let pki = TestPKI::new();
+
+        for test_certificate in &[&pki.root, &pki.intermediate, &pki.leaf] {
+            assert_eq!(
+                certificate_spki(&test_certificate.der()),
+                Some(test_certificate.public_key_der().as_slice())
+            );
+        }
+    }
+
+    #[test]
+    fn certificate_spki_truncated_certificate() {
+        let pki = TestPKI::new();
+        let der = pki.leaf.der();
+
+        assert_eq!(certificate_spki(&der[..der.len() - 1]), None);
+        assert_eq!(certificate_spki(&der[..20]), None);
+    }
+
+    #[test]
+    fn verify_leaf_pin() {
+        let pki = TestPKI::new();
+
+        assert!(verify(
+            &pki,
+            vec![pki.leaf.pin()],
+            &[pki.leaf.der(), pki.intermediate.der()]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn verify_intermediate_pin() {
+        let pki = TestPKI::new();
+
+        assert!(verify(
+            &pki,
+            vec![pki.intermediate.pin()],
+            &[pki.leaf.der(), pki.intermediate.der()]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn verify_pin_mismatch() {
+        let pki = TestPKI::new();
+
+        assert_pin_validation_error(verify(
+            &pki,
+            vec![pki.leaf_signed_by_root.pin()],
+            &[pki.leaf.der(), pki.intermediate.der()],
+        ));
+    }
+
+    #[test]
+    fn verify_pinned_certificate_outside_verified_chain() {
+        let pki = TestPKI::new();
+
+        // The chain is valid without the pinned intermediate, so presenting it is not enough.
+        assert_pin_validation_error(verify(
+            &pki,
+            vec![pki.intermediate.pin()],
+            &[pki.leaf_signed_by_root.der(), pki.intermediate.der()],
+        ));
+    }
+
+    #[test]
+    fn verify_chain_error_is_not_pin_error() {
+        let pki = TestPKI::new();
+
+        match verify(&pki, vec![pki.leaf.pin()], &[pki.leaf.der()]) {
+            Ok(_) => panic!("expected chain verification error"),
+            Err(error) => assert!(!is_spki_pin_validation_error(&error)),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::error::Error;
+use std::net::SocketAddr;
+
+use log::{debug, warn};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::doh::utils;
+
+const DEFAULT_DNS_PORT: u16 = 53;
+
+const MAX_UDP_RESPONSE_SIZE: usize = 65_535;
+
+// Header flags byte 2, RFC 1035 section 4.1.1.
+const TRUNCATION_FLAG: u8 = 0x02;
+
+fn message_id(buffer: &[u8]) -> Option<u16> {
+    if buffer.len() < 12 {
+        None
+    } else {
+        Some(u16::from_be_bytes([buffer[0], buffer[1]]))
+    }
+}
+
+fn truncated(buffer: &[u8]) -> bool {
+    (buffer[2] & TRUNCATION_FLAG) != 0
+}
+
+pub struct UDPClient {
+    server_address: SocketAddr,
+}
+
+impl UDPClient {
+    pub fn new(url: &str) -> Result<Self, Box<dyn Error>> {
+        let url = url::Url::parse(url)?;
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("udp url has no host: {}", url))?;
+
+        // Require an IP address so we never depend on another resolver to reach this one.
+        let server_address: SocketAddr =
+            format!("{}:{}", host, url.port().unwrap_or(DEFAULT_DNS_PORT))
+                .parse()
+                .map_err(|e| format!("udp url host must be an ip address: {}: {}", url, e))?;
+
+        Ok(UDPClient { server_address })
+    }
+
+    fn local_address(&self) -> SocketAddr {
+        if self.server_address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        }
+    }
+
+    async fn make_udp_request(
+        &self,
+        request_buffer: &[u8],
+        request_id: u16,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut socket = UdpSocket::bind(self.local_address()).await?;
+        socket.connect(self.server_address).await?;
+
+        socket.send(request_buffer).await?;
+
+        let mut receive_buffer = vec![0u8; MAX_UDP_RESPONSE_SIZE];
+        loop {
+            let bytes_received = socket.recv(&mut receive_buffer).await?;
+
+            match message_id(&receive_buffer[..bytes_received]) {
+                Some(id) if id == request_id => {
+                    receive_buffer.truncate(bytes_received);
+                    return Ok(receive_buffer);
+                }
+                _ => warn!(
+                    "ignoring unexpected udp response from {} length {}",
+                    self.server_address, bytes_received
+                ),
+            }
+        }
+    }
+
+    async fn make_tcp_request(
+        &self,
+        request_buffer: &[u8],
+        request_id: u16,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(self.server_address).await?;
+
+        utils::write_tcp_message(&mut stream, request_buffer).await?;
+
+        let response_buffer = utils::read_tcp_message(&mut stream).await?;
+
+        match message_id(&response_buffer) {
+            Some(id) if id == request_id => Ok(response_buffer),
+            _ => Err(format!("unexpected tcp response from {}", self.server_address).into()),
+        }
+    }
+
+    pub async fn make_plain_request(
+        &self,
+        request_buffer: Vec<u8>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let original_id = match message_id(&request_buffer) {
+            None => return Err("udp request buffer too short".into()),
+            Some(id) => id,
+        };
+
+        // Classic DNS has no transport security, so use a random ID to make spoofing harder.
+        let request_id = rand::random::<u16>();
+        let mut request_buffer = request_buffer;
+        request_buffer[..2].copy_from_slice(&request_id.to_be_bytes());
+
+        let mut response_buffer = self.make_udp_request(&request_buffer, request_id).await?;
+
+        if truncated(&response_buffer) {
+            debug!(
+                "truncated udp response from {}, retrying over tcp",
+                self.server_address
+            );
+            response_buffer = self.make_tcp_request(&request_buffer, request_id).await?;
+        }
+
+        response_buffer[..2].copy_from_slice(&original_id.to_be_bytes());
+
+        Ok(response_buffer)
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::Body;
+use hyper_rustls::HttpsConnector;
+use log::warn;
+
+use crate::doh::config::{DOHRequestMethod, UpstreamConfiguration, UpstreamType};
+use crate::doh::connector::UpstreamConnector;
+#[cfg(feature = "quic")]
+use crate::doh::doqclient::DOQClient;
+use crate::doh::dotclient::DOTClient;
+#[cfg(feature = "quic")]
+use crate::doh::http3client::HTTP3Client;
+use crate::doh::odohclient::ODOHClient;
+use crate::doh::tls;
+use crate::doh::udpclient::UDPClient;
+
+// Number of recent response times kept for percentile calculations.
+const RECENT_RESPONSE_TIMES_LEN: usize = 100;
+
+struct UpstreamStatsValues {
+    tried: bool,
+    average_response_time_milliseconds: Option<f64>,
+    average_error_rate: f64,
+    recent_response_times: VecDeque<Duration>,
+}
+
+pub struct UpstreamStats {
+    smoothing_factor: f64,
+    error_penalty_milliseconds: f64,
+    values: Mutex<UpstreamStatsValues>,
+}
+
+impl UpstreamStats {
+    fn new(smoothing_factor: f64, error_penalty_milliseconds: f64) -> Self {
+        UpstreamStats {
+            smoothing_factor,
+            error_penalty_milliseconds,
+            values: Mutex::new(UpstreamStatsValues {
+                tried: false,
+                average_response_time_milliseconds: None,
+                average_error_rate: 0.0,
+                recent_response_times: VecDeque::with_capacity(RECENT_RESPONSE_TIMES_LEN),
+            }),
+        }
+    }
+
+    fn moving_average(&self, average: f64, sample: f64) -> f64 {
+        average + (self.smoothing_factor * (sample - average))
+    }
+
+    fn record_response_time(&self, values: &mut UpstreamStatsValues, response_time: Duration) {
+        let response_time_milliseconds = response_time.as_secs_f64() * 1000.0;
+
+        values.average_response_time_milliseconds =
+            Some(match values.average_response_time_milliseconds {
+                None => response_time_milliseconds,
+                Some(average) => self.moving_average(average, response_time_milliseconds),
+            });
+    }
+
+    pub fn record_success(&self, response_time: Duration) {
+        let mut values = self.values.lock().unwrap();
+
+        values.tried = true;
+        self.record_response_time(&mut values, response_time);
+        values.average_error_rate = self.moving_average(values.average_error_rate, 0.0);
+
+        if values.recent_response_times.len() >= RECENT_RESPONSE_TIMES_LEN {
+            values.recent_response_times.pop_front();
+        }
+        values.recent_response_times.push_back(response_time);
+    }
+
+    // A timeout also counts the timeout duration as a response time.
+    pub fn record_failure(&self, timeout: Option<Duration>) {
+        let mut values = self.values.lock().unwrap();
+
+        values.tried = true;
+        if let Some(timeout) = timeout {
+            self.record_response_time(&mut values, timeout);
+        }
+        values.average_error_rate = self.moving_average(values.average_error_rate, 1.0);
+    }
+
+    // Lower is better.  An upstream that has never been tried scores 0 so it gets tried, and one
+    // that has only failed without a response time scores infinity so it is tried last.
+    pub fn score(&self) -> f64 {
+        let values = self.values.lock().unwrap();
+
+        if !values.tried {
+            return 0.0;
+        }
+
+        match values.average_response_time_milliseconds {
+            None => f64::INFINITY,
+            Some(average_response_time_milliseconds) => {
+                average_response_time_milliseconds
+                    + (values.average_error_rate * self.error_penalty_milliseconds)
+            }
+        }
+    }
+
+    // Nearest-rank percentile of recent successful response times, None if there are none.
+    pub fn response_time_percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut response_times: Vec<Duration> = self
+            .values
+            .lock()
+            .unwrap()
+            .recent_response_times
+            .iter()
+            .cloned()
+            .collect();
+
+        if response_times.is_empty() {
+            return None;
+        }
+
+        response_times.sort();
+
+        let rank = ((percentile / 100.0) * (response_times.len() as f64)).ceil() as usize;
+        let index = rank.clamp(1, response_times.len()) - 1;
+
+        Some(response_times[index])
+    }
+}
+
+struct UpstreamHealthValues {
+    consecutive_failures: u32,
+    healthy: bool,
+}
+
+// Circuit breaker state.  An upstream is marked down after consecutive_failure_threshold
+// consecutive failures, and marked up again on the next success.
+pub struct UpstreamHealth {
+    consecutive_failure_threshold: Option<u32>,
+    values: Mutex<UpstreamHealthValues>,
+}
+
+impl UpstreamHealth {
+    fn new(consecutive_failure_threshold: Option<u32>) -> Self {
+        UpstreamHealth {
+            consecutive_failure_threshold,
+            values: Mutex::new(UpstreamHealthValues {
+                consecutive_failures: 0,
+                healthy: true,
+            }),
+        }
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.values.lock().unwrap().healthy
+    }
+
+    // Returns true if this success marked the upstream up.
+    pub fn record_success(&self) -> bool {
+        let mut values = self.values.lock().unwrap();
+
+        values.consecutive_failures = 0;
+
+        if values.healthy {
+            false
+        } else {
+            values.healthy = true;
+            true
+        }
+    }
+
+    // Returns true if this failure marked the upstream down.
+    pub fn record_failure(&self) -> bool {
+        let consecutive_failure_threshold = match self.consecutive_failure_threshold {
+            None => return false,
+            Some(consecutive_failure_threshold) => consecutive_failure_threshold,
+        };
+
+        let mut values = self.values.lock().unwrap();
+
+        values.consecutive_failures = values.consecutive_failures.saturating_add(1);
+
+        if values.healthy && (values.consecutive_failures >= consecutive_failure_threshold) {
+            values.healthy = false;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub type HTTPClient = hyper::Client<HttpsConnector<UpstreamConnector>, Body>;
+
+fn build_http_client(
+    upstream_configuration: &UpstreamConfiguration,
+    upstream_connector: &UpstreamConnector,
+) -> Result<HTTPClient, Box<dyn Error>> {
+    let tls_config = tls::build_tls_config(
+        upstream_configuration.tls_configuration(),
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    )?;
+
+    Ok(hyper::Client::builder().build(HttpsConnector::from((
+        upstream_connector.clone(),
+        tls_config,
+    ))))
+}
+
+#[cfg(feature = "quic")]
+fn build_http3_client(
+    upstream_configuration: &UpstreamConfiguration,
+    upstream_connector: &UpstreamConnector,
+) -> Result<Option<HTTP3Client>, Box<dyn Error>> {
+    let http3_configuration = match upstream_configuration.http3_configuration() {
+        None => return Ok(None),
+        Some(http3_configuration) => http3_configuration,
+    };
+
+    if upstream_connector.has_egress_proxy() {
+        warn!(
+            "http3 disabled for upstream {} because an egress proxy is configured",
+            upstream_configuration.name()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(HTTP3Client::new(
+        upstream_configuration.url(),
+        upstream_configuration.request_method(),
+        upstream_configuration.tls_configuration(),
+        http3_configuration,
+        upstream_connector.clone(),
+    )?))
+}
+
+#[cfg(feature = "quic")]
+fn build_doh_transport(
+    upstream_configuration: &UpstreamConfiguration,
+    upstream_connector: &UpstreamConnector,
+) -> Result<UpstreamTransport, Box<dyn Error>> {
+    Ok(UpstreamTransport::DOH(
+        build_http_client(upstream_configuration, upstream_connector)?,
+        build_http3_client(upstream_configuration, upstream_connector)?,
+    ))
+}
+
+#[cfg(not(feature = "quic"))]
+fn build_doh_transport(
+    upstream_configuration: &UpstreamConfiguration,
+    upstream_connector: &UpstreamConnector,
+) -> Result<UpstreamTransport, Box<dyn Error>> {
+    if upstream_configuration.http3_configuration().is_some() {
+        return Err(format!(
+            "upstream {} http3_configuration requires the quic feature",
+            upstream_configuration.name()
+        )
+        .into());
+    }
+
+    Ok(UpstreamTransport::DOH(build_http_client(
+        upstream_configuration,
+        upstream_connector,
+    )?))
+}
+
+#[cfg(feature = "quic")]
+fn build_doq_transport(
+    upstream_configuration: &UpstreamConfiguration,
+    upstream_connector: &UpstreamConnector,
+) -> Result<UpstreamTransport, Box<dyn Error>> {
+    if upstream_connector.has_egress_proxy() {
+        return Err(format!(
+            "doq upstream {} cannot be used with an egress proxy",
+            upstream_configuration.name()
+        )
+        .into());
+    }
+
+    Ok(UpstreamTransport::DOQ(DOQClient::new(
+        upstream_configuration.url(),
+        upstream_configuration.tls_configuration(),
+        Duration::from_secs(upstream_configuration.request_timeout_seconds()),
+        upstream_connector.clone(),
+    )?))
+}
+
+#[cfg(not(feature = "quic"))]
+fn build_doq_transport(
+    upstream_configuration: &UpstreamConfiguration,
+    _upstream_connector: &UpstreamConnector,
+) -> Result<UpstreamTransport, Box<dyn Error>> {
+    Err(format!(
+        "doq upstream {} requires the quic feature",
+        upstream_configuration.name()
+    )
+    .into())
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub enum UpstreamTransport {
+    DOH(HTTPClient, #[cfg(feature = "quic")] Option<HTTP3Client>),
+    DOT(DOTClient),
+    UDP(UDPClient),
+    ODOH(ODOHClient),
+    #[cfg(feature = "quic")]
+    DOQ(DOQClient),
+}
+
+impl UpstreamTransport {
+    fn new(
+        upstream_configuration: &UpstreamConfiguration,
+        upstream_connector: &UpstreamConnector,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(match upstream_configuration.upstream_type() {
+            UpstreamType::DOH => build_doh_transport(upstream_configuration, upstream_connector)?,
+            UpstreamType::DOT => UpstreamTransport::DOT(DOTClient::new(
+                upstream_configuration.url(),
+                upstream_configuration.tls_configuration(),
+                upstream_connector.clone(),
+            )?),
+            UpstreamType::UDP => {
+                UpstreamTransport::UDP(UDPClient::new(upstream_configuration.url())?)
+            }
+            UpstreamType::ODOH => {
+                let odoh_configuration =
+                    upstream_configuration.odoh_configuration().ok_or_else(|| {
+                        format!(
+                            "odoh upstream {} requires odoh_configuration",
+                            upstream_configuration.name()
+                        )
+                    })?;
+                UpstreamTransport::ODOH(ODOHClient::new(
+                    upstream_configuration.url(),
+                    odoh_configuration,
+                    build_http_client(upstream_configuration, upstream_connector)?,
+                )?)
+            }
+            UpstreamType::DOQ => build_doq_transport(upstream_configuration, upstream_connector)?,
+        })
+    }
+}
+
+pub struct Upstream {
+    upstream_configuration: UpstreamConfiguration,
+    transport: UpstreamTransport,
+    request_timeout_duration: Duration,
+    stats: UpstreamStats,
+    health: UpstreamHealth,
+}
+
+impl Upstream {
+    pub fn new(
+        upstream_configuration: UpstreamConfiguration,
+        upstream_connector: &UpstreamConnector,
+        smoothing_factor: f64,
+        error_penalty_milliseconds: f64,
+        consecutive_failure_threshold: Option<u32>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let transport = UpstreamTransport::new(&upstream_configuration, upstream_connector)?;
+        if upstream_configuration.edns_padding() && matches!(transport, UpstreamTransport::UDP(_)) {
+            warn!(
+                "ignoring edns_padding for unencrypted upstream {}",
+                upstream_configuration.name()
+            );
+        }
+        let request_timeout_duration =
+            Duration::from_secs(upstream_configuration.request_timeout_seconds());
+        Ok(Upstream {
+            upstream_configuration,
+            transport,
+            request_timeout_duration,
+            stats: UpstreamStats::new(smoothing_factor, error_penalty_milliseconds),
+            health: UpstreamHealth::new(consecutive_failure_threshold),
+        })
+    }
+
+    pub fn name(&self) -> &String {
+        self.upstream_configuration.name()
+    }
+
+    pub fn use_by_default(&self) -> bool {
+        self.upstream_configuration.use_by_default()
+    }
+
+    pub fn transport(&self) -> &UpstreamTransport {
+        &self.transport
+    }
+
+    pub fn url(&self) -> &String {
+        self.upstream_configuration.url()
+    }
+
+    pub fn request_method(&self) -> DOHRequestMethod {
+        self.upstream_configuration.request_method()
+    }
+
+    // RFC 8467 section 4: padding is only useful on an encrypted transport, so plain UDP/TCP
+    // upstreams are never padded.
+    pub fn edns_padding(&self) -> bool {
+        self.upstream_configuration.edns_padding()
+            && !matches!(self.transport, UpstreamTransport::UDP(_))
+    }
+
+    pub fn request_timeout_duration(&self) -> Duration {
+        self.request_timeout_duration
+    }
+
+    pub fn stats(&self) -> &UpstreamStats {
+        &self.stats
+    }
+
+    pub fn health(&self) -> &UpstreamHealth {
+        &self.health
+    }
+}
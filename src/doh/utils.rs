@@ -1,8 +1,40 @@
+use std::convert::TryFrom;
+use std::error::Error;
+
 use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use trust_dns_proto::error::ProtoResult;
-use trust_dns_proto::op::Message;
+use trust_dns_proto::op::{Message, Query, ResponseCode};
+use trust_dns_proto::rr::RecordType;
 use trust_dns_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder};
 
+// Walks the error source chain returning true if any error matches.
+pub fn error_chain_contains(
+    error: &(dyn Error + 'static),
+    matches: &dyn Fn(&(dyn Error + 'static)) -> bool,
+) -> bool {
+    let mut error_option = Some(error);
+
+    while let Some(error) = error_option {
+        if matches(error) {
+            return true;
+        }
+
+        // io::Error::source skips over the wrapped error, so look at it directly.
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            if let Some(inner_error) = io_error.get_ref() {
+                if error_chain_contains(inner_error, matches) {
+                    return true;
+                }
+            }
+        }
+
+        error_option = error.source();
+    }
+
+    false
+}
+
 pub fn encode_dns_message(message: Message) -> ProtoResult<Vec<u8>> {
     let mut request_buffer = Vec::new();
 
@@ -32,3 +64,139 @@ pub fn decode_dns_message(buffer: Vec<u8>) -> ProtoResult<Message> {
         }
     }
 }
+
+// RFC 2308 section 2: NXDOMAIN, or NODATA which is NOERROR with no answer of the query type.
+// The answer section of a NODATA response can still hold a CNAME chain (section 2.2).
+pub fn is_negative_response(response_message: &Message) -> bool {
+    match response_message.response_code() {
+        ResponseCode::NXDomain => true,
+        ResponseCode::NoError => {
+            let query_type = response_message.queries().first().map(Query::query_type);
+            let cname_query = matches!(query_type, Some(RecordType::CNAME | RecordType::ANY));
+
+            response_message
+                .answers()
+                .iter()
+                .all(|record| (record.rr_type() == RecordType::CNAME) && !cname_query)
+        }
+        _ => false,
+    }
+}
+
+// RFC 1035 section 4.2.2: TCP messages are prefixed with a two byte length field.
+// Returns an empty buffer if the length field is 0.
+pub async fn read_tcp_message<R>(reader: &mut R) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = [0u8; 2];
+    reader.read_exact(&mut buffer).await?;
+    let length = u16::from_be_bytes(buffer);
+
+    let mut buffer = vec![0u8; usize::from(length)];
+    reader.read_exact(&mut buffer).await?;
+
+    Ok(buffer)
+}
+
+pub async fn write_tcp_message<W>(writer: &mut W, buffer: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let length = match u16::try_from(buffer.len()) {
+        Ok(length) => length,
+        Err(e) => {
+            warn!("tcp message buffer.len overflow {}: {}", buffer.len(), e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+    };
+
+    let mut length_and_buffer = Vec::with_capacity(buffer.len() + 2);
+    length_and_buffer.extend_from_slice(&length.to_be_bytes());
+    length_and_buffer.extend_from_slice(buffer);
+
+    writer.write_all(&length_and_buffer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use trust_dns_proto::op::MessageType;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    fn build_response_message(
+        response_code: ResponseCode,
+        query_type: RecordType,
+        answers: Vec<RData>,
+    ) -> Message {
+        let name = Name::from_ascii("www.example.com.").unwrap();
+
+        let mut response_message = Message::new();
+        response_message
+            .set_message_type(MessageType::Response)
+            .set_response_code(response_code)
+            .add_query(Query::query(name.clone(), query_type));
+        for rdata in answers {
+            response_message.add_answer(Record::from_rdata(name.clone(), 300, rdata));
+        }
+
+        response_message
+    }
+
+    #[test]
+    fn is_negative_response_by_response_code() {
+        let a = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert!(is_negative_response(&build_response_message(
+            ResponseCode::NXDomain,
+            RecordType::A,
+            Vec::new()
+        )));
+        assert!(is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::A,
+            Vec::new()
+        )));
+        assert!(!is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::A,
+            vec![a]
+        )));
+        assert!(!is_negative_response(&build_response_message(
+            ResponseCode::ServFail,
+            RecordType::A,
+            Vec::new()
+        )));
+    }
+
+    #[test]
+    fn is_negative_response_cname_chain() {
+        let cname = || RData::CNAME(Name::from_ascii("example.com.").unwrap());
+        let a = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+
+        // A CNAME chain that does not end in the query type is NODATA.
+        assert!(is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::A,
+            vec![cname(), cname()]
+        )));
+        assert!(!is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::A,
+            vec![cname(), a]
+        )));
+        assert!(!is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::CNAME,
+            vec![cname()]
+        )));
+        assert!(!is_negative_response(&build_response_message(
+            ResponseCode::NoError,
+            RecordType::ANY,
+            vec![cname()]
+        )));
+    }
+}